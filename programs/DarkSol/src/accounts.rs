@@ -0,0 +1,576 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar,
+};
+use solana_sdk_ids::system_program;
+
+use crate::error::DarksolError;
+use crate::{DepositRequest, TransferRequest, WithdrawRequest};
+
+// check_signer ensures the account signed the transaction
+pub fn check_signer(account: &AccountInfo) -> ProgramResult {
+    if !account.is_signer {
+        return Err(DarksolError::MissingSigner.into());
+    }
+    Ok(())
+}
+
+// check_writable ensures the account is passed as writable
+pub fn check_writable(account: &AccountInfo) -> ProgramResult {
+    if !account.is_writable {
+        return Err(DarksolError::AccountNotWritable.into());
+    }
+    Ok(())
+}
+
+// check_owner ensures the account is owned by the given program
+pub fn check_owner(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if account.owner != owner {
+        return Err(DarksolError::InvalidAccountOwner.into());
+    }
+    Ok(())
+}
+
+// check_address ensures the account is the expected account or PDA
+pub fn check_address(account: &AccountInfo, address: &Pubkey) -> ProgramResult {
+    if account.key != address {
+        return Err(DarksolError::InvalidAccountAddress.into());
+    }
+    Ok(())
+}
+
+// check_system_program ensures the account is the system program
+pub fn check_system_program(account: &AccountInfo) -> ProgramResult {
+    if account.key != &system_program::ID {
+        return Err(DarksolError::InvalidSystemProgram.into());
+    }
+    Ok(())
+}
+
+// check_token_program ensures the account is the SPL token or Token-2022 program
+pub fn check_token_program(account: &AccountInfo) -> ProgramResult {
+    if account.key != &spl_token::ID && account.key != &spl_token_2022::ID {
+        return Err(DarksolError::InvalidTokenProgram.into());
+    }
+    Ok(())
+}
+
+// check_rent_sysvar ensures the account is the rent sysvar
+pub fn check_rent_sysvar(account: &AccountInfo) -> ProgramResult {
+    if account.key != &sysvar::rent::ID {
+        return Err(DarksolError::InvalidRentSysvar.into());
+    }
+    Ok(())
+}
+
+// check_verification_program ensures the verification account is an
+// executable program and the verification key registry is owned by it
+pub fn check_verification_program(
+    verification_program: &AccountInfo,
+    vk_registry_account: &AccountInfo,
+) -> ProgramResult {
+    if !verification_program.executable {
+        return Err(DarksolError::InvalidVerificationProgram.into());
+    }
+    check_owner(vk_registry_account, verification_program.key)
+}
+
+// check_funding_account ensures the account is the funding PDA paying
+// for the program owned accounts
+fn check_funding_account(program_id: &Pubkey, funding_account: &AccountInfo) -> ProgramResult {
+    let (funding_pda, _bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], program_id);
+    check_address(funding_account, &funding_pda)?;
+    check_writable(funding_account)
+}
+
+// check_commitments_manager ensures the account is the initialized
+// commitments manager, it is updated when a new tree is created
+fn check_commitments_manager(
+    program_id: &Pubkey,
+    commitments_manager_account: &AccountInfo,
+) -> ProgramResult {
+    let (commitments_manager_pda, _bump_seed) =
+        Pubkey::find_program_address(&[b"commitments_manager_pda"], program_id);
+    check_address(commitments_manager_account, &commitments_manager_pda)?;
+    check_owner(commitments_manager_account, program_id)?;
+    check_writable(commitments_manager_account)
+}
+
+// next_writable_accounts takes the next count accounts, all of them must be writable
+fn next_writable_accounts<'a, 'info>(
+    accounts_iter: &mut std::slice::Iter<'a, AccountInfo<'info>>,
+    count: usize,
+) -> Result<Vec<&'a AccountInfo<'info>>, ProgramError> {
+    (0..count)
+        .map(|_| {
+            let account = next_account_info(accounts_iter)?;
+            check_writable(account)?;
+            Ok(account)
+        })
+        .collect()
+}
+
+// next_optional_account takes the next account if the client passed it,
+// the commitments accounts are only required when leaves are inserted
+fn next_optional_account<'a, 'info>(
+    accounts_iter: &mut std::slice::Iter<'a, AccountInfo<'info>>,
+) -> Option<&'a AccountInfo<'info>> {
+    accounts_iter.next()
+}
+
+// DepositAccounts is the accounts of the deposit instruction, native SOL
+// deposit does not take the user token account, mint, token program and
+// rent sysvar
+pub struct DepositAccounts<'a, 'info> {
+    pub funding: &'a AccountInfo<'info>,
+    pub user_wallet: &'a AccountInfo<'info>, // User's SOL wallet (payer)
+    pub user_token: Option<&'a AccountInfo<'info>>, // User's SPL token account
+    pub vault: &'a AccountInfo<'info>, // PDA vault of the deposited token
+    pub mint: Option<&'a AccountInfo<'info>>, // SPL Token Mint
+    pub commitments: &'a AccountInfo<'info>, // current commitments account
+    pub commitments_manager: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    pub fee_config: &'a AccountInfo<'info>, // fee config of the deposited token
+    pub treasury: &'a AccountInfo<'info>, // treasury receiving the deposit fee
+    pub token_program: Option<&'a AccountInfo<'info>>, // SPL Token Program
+    pub system_program: &'a AccountInfo<'info>,
+    pub rent_sysvar: Option<&'a AccountInfo<'info>>,
+    pub new_commitments: Option<&'a AccountInfo<'info>>, // new commitments account when the current tree is full
+}
+
+impl<'a, 'info> DepositAccounts<'a, 'info> {
+    // parse takes the deposit accounts in the instruction order and
+    // validates them, the mint must be the token id of the note. The
+    // config, fee config and vault addresses are checked where they are
+    // loaded
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'info>],
+        request: &DepositRequest,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let is_native = request.pre_commitments.is_native();
+
+        let funding = next_account_info(accounts_iter)?;
+        let user_wallet = next_account_info(accounts_iter)?;
+        let user_token = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let vault = next_account_info(accounts_iter)?;
+        let mint = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let commitments = next_account_info(accounts_iter)?;
+        let commitments_manager = next_account_info(accounts_iter)?;
+        let config = next_account_info(accounts_iter)?;
+        let fee_config = next_account_info(accounts_iter)?;
+        let treasury = next_account_info(accounts_iter)?;
+        let token_program = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let system_program = next_account_info(accounts_iter)?;
+        let rent_sysvar = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let new_commitments = next_optional_account(accounts_iter);
+
+        check_funding_account(program_id, funding)?;
+        check_signer(user_wallet)?;
+        check_writable(user_wallet)?;
+        check_writable(vault)?;
+        check_owner(commitments, program_id)?;
+        check_writable(commitments)?;
+        check_commitments_manager(program_id, commitments_manager)?;
+        check_writable(treasury)?;
+        check_system_program(system_program)?;
+        if let Some(user_token) = user_token {
+            check_writable(user_token)?;
+        }
+        if let Some(token_program) = token_program {
+            check_token_program(token_program)?;
+        }
+        if let (Some(mint), Some(token_program)) = (mint, token_program) {
+            check_owner(mint, token_program.key)?;
+        }
+        // the note, vault and fee are all keyed by the token id, it must
+        // be the deposited mint or a worthless mint could mint any note
+        if let Some(mint) = mint {
+            if mint.key.to_bytes().as_slice() != request.pre_commitments.token_id.as_slice() {
+                return Err(DarksolError::InvalidTokenId.into());
+            }
+        }
+        if let Some(rent_sysvar) = rent_sysvar {
+            check_rent_sysvar(rent_sysvar)?;
+        }
+        if let Some(new_commitments) = new_commitments {
+            check_writable(new_commitments)?;
+        }
+
+        Ok(Self {
+            funding,
+            user_wallet,
+            user_token,
+            vault,
+            mint,
+            commitments,
+            commitments_manager,
+            config,
+            fee_config,
+            treasury,
+            token_program,
+            system_program,
+            rent_sysvar,
+            new_commitments,
+        })
+    }
+}
+
+// RelayerAccounts is the accounts paying the relayer fee, the token
+// program is only taken for SPL tokens
+pub struct RelayerAccounts<'a, 'info> {
+    pub relayer: &'a AccountInfo<'info>, // relayer token account, or wallet for native SOL
+    pub vault: &'a AccountInfo<'info>, // PDA vault of the fee token
+    pub token_program: Option<&'a AccountInfo<'info>>,
+}
+
+// TransferAccounts is the accounts of the transfer instruction
+pub struct TransferAccounts<'a, 'info> {
+    pub user_wallet: &'a AccountInfo<'info>,
+    pub current_commitments: &'a AccountInfo<'info>, // current commitments account
+    pub commitments_manager: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    pub verification_program: &'a AccountInfo<'info>,
+    pub vk_registry: &'a AccountInfo<'info>, // verification key registry of the verification program
+    pub funding: &'a AccountInfo<'info>, // funding account pays for new commitments accounts
+    pub system_program: &'a AccountInfo<'info>,
+    pub payer: &'a AccountInfo<'info>, // pays the nullifier accounts rent: the user wallet, or the relayer wallet
+    pub relayer: Option<RelayerAccounts<'a, 'info>>,
+    pub spent_commitments: Vec<&'a AccountInfo<'info>>, // commitments account of each spent tree
+    pub nullifiers: Vec<&'a AccountInfo<'info>>, // nullifier account of each nullifier
+    pub new_commitments: Option<&'a AccountInfo<'info>>, // new commitments account when the current tree is full
+}
+
+impl<'a, 'info> TransferAccounts<'a, 'info> {
+    // parse takes the transfer accounts in the instruction order and
+    // validates them. The spent commitments accounts are checked against
+    // the spent trees and the nullifier accounts against the nullifiers
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'info>],
+        request: &TransferRequest,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_wallet = next_account_info(accounts_iter)?;
+        let current_commitments = next_account_info(accounts_iter)?;
+        let commitments_manager = next_account_info(accounts_iter)?;
+        let config = next_account_info(accounts_iter)?;
+        let verification_program = next_account_info(accounts_iter)?;
+        let vk_registry = next_account_info(accounts_iter)?;
+        let funding = next_account_info(accounts_iter)?;
+        let system_program = next_account_info(accounts_iter)?;
+        // relayer mode takes the relayer wallet signing the transaction, the
+        // relayer account, the vault of the fee token and the token program
+        // for SPL tokens
+        let mut payer = user_wallet;
+        let relayer = match &request.relayer {
+            Some(relayer) => {
+                payer = next_account_info(accounts_iter)?;
+                let relayer_account = next_account_info(accounts_iter)?;
+                let vault = next_account_info(accounts_iter)?;
+                let token_program = if relayer.is_native() {
+                    None
+                } else {
+                    Some(next_account_info(accounts_iter)?)
+                };
+
+                check_writable(relayer_account)?;
+                check_writable(vault)?;
+                if let Some(token_program) = token_program {
+                    check_token_program(token_program)?;
+                }
+
+                Some(RelayerAccounts { relayer: relayer_account, vault, token_program })
+            }
+            None => None,
+        };
+        let spent_commitments = (0..request.spent_trees.len())
+            .map(|_| next_account_info(accounts_iter))
+            .collect::<Result<Vec<_>, _>>()?;
+        let nullifiers = next_writable_accounts(accounts_iter, request.nullifiers.len())?;
+        let new_commitments = next_optional_account(accounts_iter);
+
+        // a relayer submits the transfer on behalf of the user and is
+        // bound by the proof, the signer pays the nullifier accounts
+        check_signer(payer)?;
+        check_writable(payer)?;
+        check_owner(current_commitments, program_id)?;
+        check_writable(current_commitments)?;
+        check_commitments_manager(program_id, commitments_manager)?;
+        check_verification_program(verification_program, vk_registry)?;
+        check_funding_account(program_id, funding)?;
+        check_system_program(system_program)?;
+        if let Some(new_commitments) = new_commitments {
+            check_writable(new_commitments)?;
+        }
+
+        Ok(Self {
+            user_wallet,
+            current_commitments,
+            commitments_manager,
+            config,
+            verification_program,
+            vk_registry,
+            funding,
+            system_program,
+            payer,
+            relayer,
+            spent_commitments,
+            nullifiers,
+            new_commitments,
+        })
+    }
+}
+
+// WithdrawAccounts is the accounts of the withdraw instruction, native
+// SOL withdraw pays lamports to the user wallet directly and does not
+// take the user token account and token program
+pub struct WithdrawAccounts<'a, 'info> {
+    pub funding: &'a AccountInfo<'info>,
+    pub commitments_manager: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    pub fee_config: &'a AccountInfo<'info>, // fee config of the withdrawn token
+    pub treasury: &'a AccountInfo<'info>, // treasury receiving the withdraw fee
+    pub user_wallet: &'a AccountInfo<'info>,
+    pub user_token: Option<&'a AccountInfo<'info>>, // recipient SPL token account
+    pub vault: &'a AccountInfo<'info>, // PDA vault of the withdrawn token
+    pub token_program: Option<&'a AccountInfo<'info>>,
+    pub verification_program: &'a AccountInfo<'info>,
+    pub vk_registry: &'a AccountInfo<'info>, // verification key registry of the verification program
+    pub system_program: &'a AccountInfo<'info>,
+    pub payer: &'a AccountInfo<'info>, // pays the nullifier accounts rent: the user wallet, or the relayer wallet
+    pub relayer: Option<&'a AccountInfo<'info>>, // relayer paid with the withdrawn token
+    pub spent_commitments: Vec<&'a AccountInfo<'info>>, // commitments account of each spent tree
+    pub nullifiers: Vec<&'a AccountInfo<'info>>, // nullifier account of each nullifier
+    pub current_commitments: Option<&'a AccountInfo<'info>>, // current commitments account when there are new commitments
+    pub new_commitments: Option<&'a AccountInfo<'info>>, // new commitments account when the current tree is full
+}
+
+impl<'a, 'info> WithdrawAccounts<'a, 'info> {
+    // parse takes the withdraw accounts in the instruction order and
+    // validates them
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'info>],
+        request: &WithdrawRequest,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let is_native = request.pre_commitments.is_native();
+
+        let funding = next_account_info(accounts_iter)?;
+        let commitments_manager = next_account_info(accounts_iter)?;
+        let config = next_account_info(accounts_iter)?;
+        let fee_config = next_account_info(accounts_iter)?;
+        let treasury = next_account_info(accounts_iter)?;
+        let user_wallet = next_account_info(accounts_iter)?;
+        let user_token = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let vault = next_account_info(accounts_iter)?;
+        let token_program = if is_native { None } else { Some(next_account_info(accounts_iter)?) };
+        let verification_program = next_account_info(accounts_iter)?;
+        let vk_registry = next_account_info(accounts_iter)?;
+        let system_program = next_account_info(accounts_iter)?;
+        // relayer mode takes the relayer wallet signing the transaction
+        // and the relayer account
+        let mut payer = user_wallet;
+        let relayer = match &request.relayer {
+            Some(_) => {
+                payer = next_account_info(accounts_iter)?;
+                Some(next_account_info(accounts_iter)?)
+            }
+            None => None,
+        };
+        let spent_commitments = (0..request.spent_trees.len())
+            .map(|_| next_account_info(accounts_iter))
+            .collect::<Result<Vec<_>, _>>()?;
+        let nullifiers = next_writable_accounts(accounts_iter, request.nullifiers.len())?;
+        let current_commitments = next_optional_account(accounts_iter);
+        let new_commitments = next_optional_account(accounts_iter);
+
+        check_funding_account(program_id, funding)?;
+        check_commitments_manager(program_id, commitments_manager)?;
+        check_writable(treasury)?;
+        // a relayer submits the withdraw on behalf of the user and can not
+        // change the recipient bound by the proof, the signer pays the
+        // nullifier accounts
+        check_signer(payer)?;
+        check_writable(payer)?;
+        check_writable(vault)?;
+        check_verification_program(verification_program, vk_registry)?;
+        check_system_program(system_program)?;
+        match (user_token, token_program) {
+            (Some(user_token), Some(token_program)) => {
+                check_writable(user_token)?;
+                check_token_program(token_program)?;
+            }
+            // native SOL is paid to the user wallet
+            _ => check_writable(user_wallet)?,
+        }
+        if let Some(relayer) = relayer {
+            check_writable(relayer)?;
+        }
+        if let Some(current_commitments) = current_commitments {
+            check_owner(current_commitments, program_id)?;
+            check_writable(current_commitments)?;
+        }
+        if let Some(new_commitments) = new_commitments {
+            check_writable(new_commitments)?;
+        }
+
+        Ok(Self {
+            funding,
+            commitments_manager,
+            config,
+            fee_config,
+            treasury,
+            user_wallet,
+            user_token,
+            vault,
+            token_program,
+            verification_program,
+            vk_registry,
+            system_program,
+            payer,
+            relayer,
+            spent_commitments,
+            nullifiers,
+            current_commitments,
+            new_commitments,
+        })
+    }
+}
+
+// InitializeAccounts is the accounts of the initialize instruction
+pub struct InitializeAccounts<'a, 'info> {
+    pub payer: &'a AccountInfo<'info>, // program upgrade authority, becomes the admin
+    pub funding: &'a AccountInfo<'info>,
+    pub commitments: &'a AccountInfo<'info>,
+    pub commitments_manager: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    pub program_data: &'a AccountInfo<'info>, // program data of the upgradeable program
+    pub verification_program: &'a AccountInfo<'info>, // trusted verification program stored in the config
+}
+
+impl<'a, 'info> InitializeAccounts<'a, 'info> {
+    // parse takes the initialize accounts in the instruction order and
+    // validates them, the upgrade authority is checked against the
+    // program data account
+    pub fn parse(program_id: &Pubkey, accounts: &'a [AccountInfo<'info>]) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let payer = next_account_info(accounts_iter)?;
+        let funding = next_account_info(accounts_iter)?;
+        let commitments = next_account_info(accounts_iter)?;
+        let commitments_manager = next_account_info(accounts_iter)?;
+        let system_program = next_account_info(accounts_iter)?;
+        let config = next_account_info(accounts_iter)?;
+        let program_data = next_account_info(accounts_iter)?;
+        let verification_program = next_account_info(accounts_iter)?;
+
+        check_signer(payer)?;
+        check_writable(payer)?;
+        check_funding_account(program_id, funding)?;
+        check_writable(commitments)?;
+        check_writable(commitments_manager)?;
+        check_system_program(system_program)?;
+        check_writable(config)?;
+        if !verification_program.executable {
+            return Err(DarksolError::InvalidVerificationProgram.into());
+        }
+
+        Ok(Self {
+            payer,
+            funding,
+            commitments,
+            commitments_manager,
+            system_program,
+            config,
+            program_data,
+            verification_program,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PreCommitments, ShieldCipherText};
+
+    // TestAccount holds the data an AccountInfo borrows
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        is_signer: bool,
+        is_writable: bool,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, is_signer: bool, is_writable: bool) -> Self {
+            TestAccount { key, owner, lamports: 0, data: vec![], is_signer, is_writable }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                self.is_writable,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    // deposit_accounts returns the accounts of a SPL token deposit of the mint
+    fn deposit_accounts(program_id: &Pubkey, mint: &Pubkey) -> Vec<TestAccount> {
+        let (funding_pda, _) = Pubkey::find_program_address(&[b"funding_pda"], program_id);
+        let (manager_pda, _) = Pubkey::find_program_address(&[b"commitments_manager_pda"], program_id);
+        let system = system_program::ID;
+        vec![
+            TestAccount::new(funding_pda, *program_id, false, true),
+            TestAccount::new(Pubkey::new_unique(), system, true, true), // user wallet
+            TestAccount::new(Pubkey::new_unique(), spl_token::ID, false, true), // user token account
+            TestAccount::new(Pubkey::new_unique(), spl_token::ID, false, true), // vault
+            TestAccount::new(*mint, spl_token::ID, false, false),
+            TestAccount::new(Pubkey::new_unique(), *program_id, false, true), // commitments
+            TestAccount::new(manager_pda, *program_id, false, true),
+            TestAccount::new(Pubkey::new_unique(), *program_id, false, false), // config
+            TestAccount::new(Pubkey::new_unique(), *program_id, false, false), // fee config
+            TestAccount::new(Pubkey::new_unique(), spl_token::ID, false, true), // treasury
+            TestAccount::new(spl_token::ID, system, false, false),
+            TestAccount::new(system, system, false, false),
+            TestAccount::new(sysvar::rent::ID, system, false, false),
+        ]
+    }
+
+    fn deposit_request(token_id: &Pubkey) -> DepositRequest {
+        DepositRequest::new(
+            PreCommitments::new(100, token_id.to_bytes().to_vec(), vec![1; 32]),
+            ShieldCipherText::new(vec![], vec![], vec![]),
+        )
+    }
+
+    #[test]
+    fn test_deposit_mint_is_token_id() {
+        let program_id = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut accounts = deposit_accounts(&program_id, &mint);
+        let infos: Vec<AccountInfo> = accounts.iter_mut().map(TestAccount::info).collect();
+
+        assert!(DepositAccounts::parse(&program_id, &infos, &deposit_request(&mint)).is_ok());
+
+        // a note of another token can not be minted by depositing this mint
+        let other_token = Pubkey::new_unique();
+        let result = DepositAccounts::parse(&program_id, &infos, &deposit_request(&other_token));
+        assert_eq!(result.err(), Some(DarksolError::InvalidTokenId.into()));
+    }
+}
@@ -0,0 +1,204 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::accounts::{check_address, check_owner, check_signer, check_system_program};
+use crate::error::DarksolError;
+use crate::instruction::DarkSolInstruction;
+use crate::utils::account::create_pda_account_from_payer;
+use crate::processor::{process_transfer_asset, process_withdraw_asset};
+
+// REQUEST_BUFFER_HEADER_LENGTH is the serialized length of RequestBufferHeader
+pub const REQUEST_BUFFER_HEADER_LENGTH: usize = 32 + 4;
+// MAX_REQUEST_BUFFER_SIZE is the largest request a buffer can stage,
+// accounts created in an instruction are limited to 10KB
+pub const MAX_REQUEST_BUFFER_SIZE: usize = MAX_PERMITTED_DATA_INCREASE - REQUEST_BUFFER_HEADER_LENGTH;
+
+// RequestBufferHeader is stored at the start of a request buffer account
+// and followed by the staged instruction data
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct RequestBufferHeader {
+    pub authority: Pubkey, // signer staging and executing the request
+    pub size: u32,         // length of the staged instruction data
+}
+
+// derive_request_buffer_pda derives the request buffer of the authority,
+// an authority can stage several requests with different buffer ids
+pub fn derive_request_buffer_pda(authority: &Pubkey, buffer_id: u64, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"request_buffer", authority.as_ref(), &buffer_id.to_le_bytes()],
+        program_id,
+    )
+}
+
+// load_request_buffer reads the buffer header after checking the buffer
+// address and that the authority signed
+fn load_request_buffer(
+    program_id: &Pubkey,
+    authority_account: &AccountInfo,
+    buffer_account: &AccountInfo,
+    buffer_id: u64,
+) -> Result<RequestBufferHeader, ProgramError> {
+    check_signer(authority_account)?;
+
+    let (buffer_pda, _bump_seed) = derive_request_buffer_pda(authority_account.key, buffer_id, program_id);
+    check_address(buffer_account, &buffer_pda)?;
+
+    check_owner(buffer_account, program_id)?;
+
+    let header = RequestBufferHeader::deserialize(&mut &buffer_account.data.borrow()[..])?;
+    if &header.authority != authority_account.key {
+        return Err(DarksolError::Unauthorized.into());
+    }
+
+    Ok(header)
+}
+
+// close_request_buffer returns the buffer rent to the authority, the
+// account is removed at the end of the transaction
+fn close_request_buffer(authority_account: &AccountInfo, buffer_account: &AccountInfo) -> ProgramResult {
+    let lamports = buffer_account.lamports();
+    **buffer_account.lamports.borrow_mut() = 0;
+    **authority_account.lamports.borrow_mut() += lamports;
+    buffer_account.realloc(0, false)?;
+
+    Ok(())
+}
+
+// init_request_buffer create a buffer account of the given size to
+// stage a request too large for a single transaction
+pub fn init_request_buffer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    buffer_id: u64,
+    size: u32,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let authority_account = next_account_info(accounts_iter)?; // pays the buffer rent
+    let buffer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    check_signer(authority_account)?;
+
+    check_system_program(system_program)?;
+
+    if size as usize > MAX_REQUEST_BUFFER_SIZE {
+        return Err(DarksolError::BufferSizeExceeded.into());
+    }
+
+    let (buffer_pda, bump_seed) = derive_request_buffer_pda(authority_account.key, buffer_id, program_id);
+    check_address(buffer_account, &buffer_pda)?;
+
+    if buffer_account.owner == program_id {
+        return Err(DarksolError::AccountAlreadyInitialized.into());
+    }
+
+    // the buffer address is predictable, lamports sent to it beforehand
+    // would make create_account fail so it is funded, allocated and assigned
+    let space = REQUEST_BUFFER_HEADER_LENGTH + size as usize;
+    create_pda_account_from_payer(
+        authority_account,
+        space,
+        program_id,
+        system_program,
+        buffer_account,
+        &[
+            b"request_buffer",
+            authority_account.key.as_ref(),
+            &buffer_id.to_le_bytes(),
+            &[bump_seed],
+        ],
+    )?;
+
+    let header = RequestBufferHeader {
+        authority: *authority_account.key,
+        size,
+    };
+    header.serialize(&mut &mut buffer_account.data.borrow_mut()[..])?;
+
+    msg!("request buffer {} initialized, size: {}", buffer_id, size);
+
+    Ok(())
+}
+
+// write_request_buffer writes a chunk of the staged instruction data
+// at the given offset
+pub fn write_request_buffer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    buffer_id: u64,
+    offset: u32,
+    data: Vec<u8>,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let authority_account = next_account_info(accounts_iter)?;
+    let buffer_account = next_account_info(accounts_iter)?;
+
+    let header = load_request_buffer(program_id, authority_account, buffer_account, buffer_id)?;
+
+    let end = (offset as usize)
+        .checked_add(data.len())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if end > header.size as usize {
+        return Err(DarksolError::BufferSizeExceeded.into());
+    }
+
+    let start = REQUEST_BUFFER_HEADER_LENGTH + offset as usize;
+    buffer_account.data.borrow_mut()[start..start + data.len()].copy_from_slice(&data);
+
+    Ok(())
+}
+
+// execute_from_buffer executes the transfer or withdraw staged in the
+// buffer with the accounts following the authority and buffer accounts,
+// then closes the buffer
+pub fn execute_from_buffer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    buffer_id: u64,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let authority_account = next_account_info(accounts_iter)?;
+    let buffer_account = next_account_info(accounts_iter)?;
+
+    let header = load_request_buffer(program_id, authority_account, buffer_account, buffer_id)?;
+
+    // copy the staged instruction so the buffer is not borrowed
+    // while the spend runs
+    let instruction_data = buffer_account.data.borrow()
+        [REQUEST_BUFFER_HEADER_LENGTH..REQUEST_BUFFER_HEADER_LENGTH + header.size as usize]
+        .to_vec();
+
+    let spend_accounts = &accounts[2..];
+    match DarkSolInstruction::unpack_staged(&instruction_data)? {
+        DarkSolInstruction::Transfer { request } => {
+            process_transfer_asset(program_id, spend_accounts, request)?
+        }
+        DarkSolInstruction::Withdraw { request } => {
+            process_withdraw_asset(program_id, spend_accounts, request)?
+        }
+        _ => return Err(DarksolError::InvalidInstructionData.into()),
+    };
+
+    close_request_buffer(authority_account, buffer_account)
+}
+
+// close_buffer closes a buffer without executing it
+pub fn close_buffer(program_id: &Pubkey, accounts: &[AccountInfo], buffer_id: u64) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let authority_account = next_account_info(accounts_iter)?;
+    let buffer_account = next_account_info(accounts_iter)?;
+
+    load_request_buffer(program_id, authority_account, buffer_account, buffer_id)?;
+
+    close_request_buffer(authority_account, buffer_account)
+}
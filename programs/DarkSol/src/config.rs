@@ -0,0 +1,466 @@
+use crate::accounts::{check_address, check_owner, check_signer, check_system_program};
+use crate::error::DarksolError;
+use crate::merkle::{commitments_account_space, HashFunction};
+use crate::state::CommitmentsManagerAccount;
+use crate::utils::serialize::{
+    BorshDeserializeWithLength, BorshSerializeWithLength, DATA_LENGTH_CAPACITY,
+};
+use crate::NATIVE_SOL_TOKEN_ID;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::{Pubkey, PUBKEY_BYTES},
+    sysvar::{rent::Rent, Sysvar},
+};
+use solana_sdk_ids::{bpf_loader_upgradeable, system_program};
+use solana_system_interface::instruction as system_instruction;
+use spl_token_2022::{extension::StateWithExtensions, state::Account as TokenAccount};
+
+// operations flags, each operation can be paused independently
+pub const PAUSE_DEPOSIT: u8 = 1 << 0;
+pub const PAUSE_TRANSFER: u8 = 1 << 1;
+pub const PAUSE_WITHDRAW: u8 = 1 << 2;
+pub const PAUSE_ALL: u8 = PAUSE_DEPOSIT | PAUSE_TRANSFER | PAUSE_WITHDRAW;
+
+// ConfigAccount is a single account holding the program admin,
+// the paused operations and the trusted verification program
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct ConfigAccount {
+    pub admin: Pubkey,
+    pub paused_operations: u8, // PAUSE_* flags of the paused operations
+    pub verification_program: Pubkey, // the only program proofs are verified with
+}
+
+pub const CONFIG_ACCOUNT_SPACE: usize = PUBKEY_BYTES + 1 + PUBKEY_BYTES + DATA_LENGTH_CAPACITY;
+
+impl ConfigAccount {
+    // check_not_paused fails if the operation is paused
+    pub fn check_not_paused(&self, operation: u8) -> ProgramResult {
+        if self.paused_operations & operation != 0 {
+            return Err(DarksolError::OperationPaused.into());
+        }
+
+        Ok(())
+    }
+
+    // check_verification_program fails if the verification program is not
+    // the trusted one, a caller supplied program could accept any proof
+    pub fn check_verification_program(&self, verification_program: &AccountInfo) -> ProgramResult {
+        if verification_program.key != &self.verification_program {
+            return Err(DarksolError::UntrustedVerificationProgram.into());
+        }
+
+        Ok(())
+    }
+}
+
+// ConfigParams are the program parameters the admin can update,
+// parameters left empty are unchanged
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+pub struct ConfigParams {
+    pub root_history_size: Option<u32>, // root history capacity of newly created trees
+    pub verification_program: Option<Pubkey>, // trusted verification program
+    pub hash_function: Option<u8>, // HashFunction of newly created trees
+}
+
+// derive_config_pda derives the program config account
+pub fn derive_config_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"config"], program_id)
+}
+
+// validate_root_history_size checks a new tree with `root_history_size`
+// roots can be created through a CPI which can not allocate more than
+// MAX_PERMITTED_DATA_INCREASE, larger histories use extend tree
+pub fn validate_root_history_size(root_history_size: u32) -> ProgramResult {
+    if root_history_size == 0
+        || commitments_account_space(root_history_size as usize) > MAX_PERMITTED_DATA_INCREASE
+    {
+        return Err(DarksolError::InvalidInstructionData.into());
+    }
+
+    Ok(())
+}
+
+// check_upgrade_authority ensures the authority is the upgrade
+// authority of the program so only the deployer can initialize it
+pub fn check_upgrade_authority(
+    program_id: &Pubkey,
+    program_data_account: &AccountInfo,
+    authority: &AccountInfo,
+) -> ProgramResult {
+    let (program_data_address, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    check_address(program_data_account, &program_data_address)?;
+    check_owner(program_data_account, &bpf_loader_upgradeable::id())?;
+
+    check_signer(authority)?;
+
+    // UpgradeableLoaderState::ProgramData layout:
+    // u32 variant ( 3 ) | u64 slot | option tag | upgrade authority
+    let data = program_data_account.data.borrow();
+    if data.len() < 13 + PUBKEY_BYTES || data[..4] != 3u32.to_le_bytes() || data[12] != 1 {
+        return Err(DarksolError::Unauthorized.into());
+    }
+    if &data[13..13 + PUBKEY_BYTES] != authority.key.as_ref() {
+        return Err(DarksolError::Unauthorized.into());
+    }
+
+    Ok(())
+}
+
+// initialize_config create the config account with the admin and the
+// trusted verification program, no operation is paused
+pub fn initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    admin: &Pubkey,
+    verification_program: &Pubkey,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let payer_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let (config_pda, bump_seed) = derive_config_pda(program_id);
+    check_address(config_account, &config_pda)?;
+
+    // account should only initialized once
+    if config_account.owner == program_id {
+        return Err(DarksolError::AccountAlreadyInitialized.into());
+    }
+
+    let rent = Rent::get()?;
+    invoke_signed(
+        &system_instruction::create_account(
+            payer_account.key,
+            &config_pda,
+            rent.minimum_balance(CONFIG_ACCOUNT_SPACE),
+            CONFIG_ACCOUNT_SPACE as u64,
+            program_id,
+        ),
+        &[
+            payer_account.clone(),
+            config_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[b"config", &[bump_seed]]],
+    )?;
+
+    let config = ConfigAccount {
+        admin: *admin,
+        paused_operations: 0,
+        verification_program: *verification_program,
+    };
+    config.serialize_with_length(&mut &mut config_account.data.borrow_mut()[..])?;
+
+    msg!("config initialized, admin: {}, verification program: {}", admin, verification_program);
+
+    Ok(())
+}
+
+// load_config reads the config account after checking its address
+pub fn load_config(program_id: &Pubkey, config_account: &AccountInfo) -> Result<ConfigAccount, ProgramError> {
+    let (config_pda, _bump_seed) = derive_config_pda(program_id);
+    check_address(config_account, &config_pda)?;
+
+    check_owner(config_account, program_id)?;
+
+    Ok(ConfigAccount::try_from_slice_with_length(&config_account.data.borrow())?)
+}
+
+// check_not_paused fails if the operation is paused
+pub fn check_not_paused(program_id: &Pubkey, config_account: &AccountInfo, operation: u8) -> ProgramResult {
+    load_config(program_id, config_account)?.check_not_paused(operation)
+}
+
+// load_config_as_admin reads the config account and ensures
+// the admin account is the config admin and signed the transaction
+fn load_config_as_admin(
+    program_id: &Pubkey,
+    admin_account: &AccountInfo,
+    config_account: &AccountInfo,
+) -> Result<ConfigAccount, ProgramError> {
+    check_signer(admin_account)?;
+
+    let config = load_config(program_id, config_account)?;
+    if &config.admin != admin_account.key {
+        return Err(DarksolError::Unauthorized.into());
+    }
+
+    Ok(config)
+}
+
+// set_paused pause or unpause the operations of the PAUSE_* flags
+pub fn set_paused(program_id: &Pubkey, accounts: &[AccountInfo], operations: u8, paused: bool) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+
+    if operations == 0 || operations & !PAUSE_ALL != 0 {
+        return Err(DarksolError::InvalidInstructionData.into());
+    }
+
+    let mut config = load_config_as_admin(program_id, admin_account, config_account)?;
+    if paused {
+        config.paused_operations |= operations;
+    } else {
+        config.paused_operations &= !operations;
+    }
+    config.serialize_with_length(&mut &mut config_account.data.borrow_mut()[..])?;
+
+    msg!("paused operations: {}", config.paused_operations);
+
+    Ok(())
+}
+
+// set_admin transfer the admin role to a new admin
+pub fn set_admin(program_id: &Pubkey, accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+
+    let mut config = load_config_as_admin(program_id, admin_account, config_account)?;
+    config.admin = new_admin;
+    config.serialize_with_length(&mut &mut config_account.data.borrow_mut()[..])?;
+
+    msg!("new admin: {}", new_admin);
+
+    Ok(())
+}
+
+// update_config update the program parameters
+pub fn update_config(program_id: &Pubkey, accounts: &[AccountInfo], params: ConfigParams) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let commitments_manager_account = next_account_info(accounts_iter)?;
+    // only required to grow a commitments manager created with an older layout
+    let funding_account = accounts_iter.next();
+
+    let mut config = load_config_as_admin(program_id, admin_account, config_account)?;
+
+    let (commitments_manager_pda, _bump_seed) =
+        Pubkey::find_program_address(&[b"commitments_manager_pda"], program_id);
+    check_address(commitments_manager_account, &commitments_manager_pda)?;
+
+    check_owner(commitments_manager_account, program_id)?;
+
+    if let Some(funding_account) = funding_account {
+        let (funding_pda, _funding_bump_seed) =
+            Pubkey::find_program_address(&[b"funding_pda"], program_id);
+        check_address(funding_account, &funding_pda)?;
+        check_owner(funding_account, program_id)?;
+    }
+
+    if params.root_history_size.is_some() || params.hash_function.is_some() {
+        let mut manager_data =
+            CommitmentsManagerAccount::load(&commitments_manager_account.data.borrow())?;
+
+        if let Some(root_history_size) = params.root_history_size {
+            validate_root_history_size(root_history_size)?;
+            manager_data.root_history_size = root_history_size;
+
+            msg!("new root history size: {}", root_history_size);
+        }
+
+        // the current tree keeps its hash function, the next trees use the new one
+        if let Some(hash_function) = params.hash_function {
+            HashFunction::try_from(hash_function)?;
+            manager_data.hash_function = hash_function;
+
+            msg!("new hash function: {}", hash_function);
+        }
+
+        manager_data.save(commitments_manager_account, funding_account)?;
+    }
+
+    if let Some(verification_program) = params.verification_program {
+        config.verification_program = verification_program;
+        config.serialize_with_length(&mut &mut config_account.data.borrow_mut()[..])?;
+
+        msg!("new verification program: {}", verification_program);
+    }
+
+    Ok(())
+}
+
+// MAX_FEE_BPS is 100% in basis points
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+// FeeConfigAccount holds the fees of a single token id in basis points
+// and the treasury receiving them ( a token account of the mint, or a
+// wallet for native SOL ). Tokens without fee config pay no fee
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct FeeConfigAccount {
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+    pub treasury: Pubkey,
+}
+
+pub const FEE_CONFIG_ACCOUNT_SPACE: usize = 2 + 2 + PUBKEY_BYTES + DATA_LENGTH_CAPACITY;
+
+// derive_fee_config_pda derives the fee config account of a token id
+pub fn derive_fee_config_pda(token_id: &[u8], program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"fee", token_id], program_id)
+}
+
+// compute_fee returns the fee of the amount rounded down
+pub fn compute_fee(amount: u64, fee_bps: u16) -> u64 {
+    (amount as u128 * fee_bps as u128 / MAX_FEE_BPS as u128) as u64
+}
+
+// load_fee_config reads the fee config account of the token id,
+// returns None if no fee is configured for the token
+pub fn load_fee_config(
+    program_id: &Pubkey,
+    fee_config_account: &AccountInfo,
+    token_id: &[u8],
+) -> Result<Option<FeeConfigAccount>, ProgramError> {
+    let (fee_config_pda, _bump_seed) = derive_fee_config_pda(token_id, program_id);
+    check_address(fee_config_account, &fee_config_pda)?;
+
+    if fee_config_account.owner != program_id {
+        return Ok(None);
+    }
+
+    Ok(Some(FeeConfigAccount::try_from_slice_with_length(
+        &fee_config_account.data.borrow(),
+    )?))
+}
+
+// set_fee create or update the fee config of a token id,
+// the admin pays for the fee config account
+pub fn set_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    token_id: Vec<u8>,
+    deposit_fee_bps: u16,
+    withdraw_fee_bps: u16,
+    treasury: Pubkey,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let fee_config_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let treasury_account = next_account_info(accounts_iter)?;
+
+    check_system_program(system_program)?;
+    load_config_as_admin(program_id, admin_account, config_account)?;
+
+    if token_id.len() != PUBKEY_BYTES
+        || deposit_fee_bps > MAX_FEE_BPS
+        || withdraw_fee_bps > MAX_FEE_BPS
+    {
+        return Err(DarksolError::InvalidInstructionData.into());
+    }
+    check_treasury(treasury_account, &treasury, &token_id)?;
+
+    let (fee_config_pda, bump_seed) = derive_fee_config_pda(&token_id, program_id);
+    check_address(fee_config_account, &fee_config_pda)?;
+
+    if fee_config_account.owner != program_id {
+        let rent = Rent::get()?;
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_account.key,
+                &fee_config_pda,
+                rent.minimum_balance(FEE_CONFIG_ACCOUNT_SPACE),
+                FEE_CONFIG_ACCOUNT_SPACE as u64,
+                program_id,
+            ),
+            &[
+                admin_account.clone(),
+                fee_config_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[b"fee", &token_id, &[bump_seed]]],
+        )?;
+    }
+
+    let fee_config = FeeConfigAccount {
+        deposit_fee_bps,
+        withdraw_fee_bps,
+        treasury,
+    };
+    fee_config.serialize_with_length(&mut &mut fee_config_account.data.borrow_mut()[..])?;
+
+    msg!(
+        "fee of {:?}: deposit {} bps, withdraw {} bps",
+        token_id,
+        deposit_fee_bps,
+        withdraw_fee_bps
+    );
+
+    Ok(())
+}
+
+// check_treasury ensures the treasury can receive the fees of the token,
+// a token account of the token mint or a system account for native SOL.
+// Otherwise every deposit and withdraw of the token would fail to pay it
+fn check_treasury(treasury_account: &AccountInfo, treasury: &Pubkey, token_id: &[u8]) -> ProgramResult {
+    if treasury_account.key != treasury {
+        return Err(DarksolError::InvalidTreasury.into());
+    }
+
+    if token_id == NATIVE_SOL_TOKEN_ID.as_slice() {
+        if treasury_account.owner != &system_program::ID {
+            return Err(DarksolError::InvalidTreasury.into());
+        }
+        return Ok(());
+    }
+
+    if treasury_account.owner != &spl_token::ID && treasury_account.owner != &spl_token_2022::ID {
+        return Err(DarksolError::InvalidTreasury.into());
+    }
+    let treasury_data = treasury_account.try_borrow_data()?;
+    let treasury_token_account = StateWithExtensions::<TokenAccount>::unpack(&treasury_data)
+        .map_err(|_| DarksolError::InvalidTreasury)?
+        .base;
+    if treasury_token_account.mint.as_ref() != token_id {
+        return Err(DarksolError::InvalidTreasury.into());
+    }
+
+    Ok(())
+}
+
+// FeeOperation selects which fee of the fee config applies
+pub enum FeeOperation {
+    Deposit,
+    Withdraw,
+}
+
+// operation_fee returns the fee of the operation on `amount` of the
+// token id and checks the treasury account receiving it
+pub fn operation_fee(
+    program_id: &Pubkey,
+    fee_config_account: &AccountInfo,
+    treasury_account: &AccountInfo,
+    token_id: &[u8],
+    amount: u64,
+    operation: FeeOperation,
+) -> Result<u64, ProgramError> {
+    let fee_config = match load_fee_config(program_id, fee_config_account, token_id)? {
+        Some(fee_config) => fee_config,
+        None => return Ok(0),
+    };
+
+    let fee = match operation {
+        FeeOperation::Deposit => compute_fee(amount, fee_config.deposit_fee_bps),
+        FeeOperation::Withdraw => compute_fee(amount, fee_config.withdraw_fee_bps),
+    };
+    if fee > 0 && treasury_account.key != &fee_config.treasury {
+        return Err(DarksolError::InvalidTreasury.into());
+    }
+
+    Ok(fee)
+}
@@ -1,11 +1,16 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, pubkey::Pubkey,
+    account_info::AccountInfo, entrypoint::ProgramResult, pubkey::Pubkey,
 };
+use crate::buffer::{close_buffer, execute_from_buffer, init_request_buffer, write_request_buffer};
+use crate::config::{set_admin, set_fee, set_paused, update_config};
 use crate::instruction::DarkSolInstruction;
-use crate::processor::{process_deposit_fund, process_initialize_account, process_transfer_asset, process_withdraw_asset};
+use crate::processor::{
+    process_deposit_fund, process_extend_tree, process_initialize_account, process_migrate_nullifiers, process_migrate_tree,
+    process_transfer_asset, process_verify_inclusion, process_withdraw_asset,
+};
 
 #[cfg(not(feature = "no-entrypoint"))]
-entrypoint!(process_instruction);
+solana_program::entrypoint!(process_instruction);
 
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -22,7 +27,38 @@ pub fn process_instruction(
         }
         DarkSolInstruction::Transfer { request } => process_transfer_asset(program_id, accounts, request)?,
         DarkSolInstruction::Withdraw { request } => process_withdraw_asset(program_id, accounts, request)?,
-        DarkSolInstruction::Initialize {} => process_initialize_account(program_id, accounts)?
+        DarkSolInstruction::Initialize { root_history_size } => {
+            process_initialize_account(program_id, accounts, root_history_size)?
+        }
+        DarkSolInstruction::MigrateTree { tree_number } => {
+            process_migrate_tree(program_id, accounts, tree_number)?
+        }
+        DarkSolInstruction::ExtendTree { tree_number, additional_roots } => {
+            process_extend_tree(program_id, accounts, tree_number, additional_roots)?
+        }
+        DarkSolInstruction::Pause { operations } => set_paused(program_id, accounts, operations, true)?,
+        DarkSolInstruction::Unpause { operations } => set_paused(program_id, accounts, operations, false)?,
+        DarkSolInstruction::SetAdmin { new_admin } => set_admin(program_id, accounts, new_admin)?,
+        DarkSolInstruction::UpdateConfig { params } => update_config(program_id, accounts, params)?,
+        DarkSolInstruction::SetFee { token_id, deposit_fee_bps, withdraw_fee_bps, treasury } => {
+            set_fee(program_id, accounts, token_id, deposit_fee_bps, withdraw_fee_bps, treasury)?
+        }
+        DarkSolInstruction::InitBuffer { buffer_id, size } => {
+            init_request_buffer(program_id, accounts, buffer_id, size)?
+        }
+        DarkSolInstruction::WriteBuffer { buffer_id, offset, data } => {
+            write_request_buffer(program_id, accounts, buffer_id, offset, data)?
+        }
+        DarkSolInstruction::ExecuteFromBuffer { buffer_id } => {
+            execute_from_buffer(program_id, accounts, buffer_id)?
+        }
+        DarkSolInstruction::CloseBuffer { buffer_id } => close_buffer(program_id, accounts, buffer_id)?,
+        DarkSolInstruction::VerifyInclusion { tree_number, leaf, index, path, root } => {
+            process_verify_inclusion(program_id, accounts, tree_number, leaf, index, path, root)?
+        }
+        DarkSolInstruction::MigrateNullifiers { tree_number } => {
+            process_migrate_nullifiers(program_id, accounts, tree_number)?
+        }
     };
     Ok(())
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
 //! Error types
 
-use solana_program::{decode_error::DecodeError, program_error::ProgramError};
+// DecodeError is deprecated in solana-program 2.2, kept as clients decode
+// the custom errors with it
+#[allow(deprecated)]
+use solana_program::decode_error::DecodeError;
+use solana_program::program_error::ProgramError;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum DarksolError {
@@ -51,6 +55,107 @@ pub enum DarksolError {
     // 11
     /// public values deserialize fail
     PublicValuesDeserializeFail,
+
+    // 12
+    /// token id is not a valid mint address
+    InvalidTokenId,
+
+    // 13
+    /// recipient token account mint not match the withdrawn token
+    RecipientMintNotMatch,
+
+    // 14
+    /// commitments account data is not a valid tree
+    InvalidCommitmentsAccount,
+
+    // 15
+    /// commitments account layout version is not supported
+    UnsupportedAccountVersion,
+
+    // 16
+    /// commitments account is already in the current layout
+    AccountAlreadyMigrated,
+
+    // 17
+    /// proof tree depth not match the commitments tree depth
+    TreeDepthMismatch,
+
+    // 18
+    /// signer is not the program admin or upgrade authority
+    Unauthorized,
+
+    // 19
+    /// operation is paused by the admin
+    OperationPaused,
+
+    // 20
+    /// treasury account not match the fee config treasury, or can not
+    /// receive the fees of the token
+    InvalidTreasury,
+
+    // 21
+    /// relayer account not match the relayer committed in the proof
+    RelayerNotMatch,
+
+    // 22
+    /// request buffer write out of the buffer size or size over the limit
+    BufferSizeExceeded,
+
+    // 23
+    /// account required to sign did not sign the transaction
+    MissingSigner,
+
+    // 24
+    /// account required to be writable is readonly
+    AccountNotWritable,
+
+    // 25
+    /// account is not owned by the expected program
+    InvalidAccountOwner,
+
+    // 26
+    /// account address not match the expected address or PDA
+    InvalidAccountAddress,
+
+    // 27
+    /// system program account is not the system program
+    InvalidSystemProgram,
+
+    // 28
+    /// token program account is not the SPL token or Token-2022 program
+    InvalidTokenProgram,
+
+    // 29
+    /// rent sysvar account is not the rent sysvar
+    InvalidRentSysvar,
+
+    // 30
+    /// verification program account is not an executable program
+    InvalidVerificationProgram,
+
+    // 31
+    /// verification program is not the trusted verification program of the config
+    UntrustedVerificationProgram,
+
+    // 32
+    /// hash function is not a supported commitments tree hash function
+    UnsupportedHashFunction,
+
+    // 33
+    /// merkle path does not prove the commitment is in the tree
+    InvalidInclusionProof,
+
+    // 34
+    /// legacy commitments account still holds nullifiers not moved to the registry
+    NullifiersNotMigrated,
+
+    // 35
+    /// commitments account is still in the legacy layout and must be migrated
+    TreeNotMigrated,
+
+    // 36
+    /// Poseidon input or leaf is not a canonical BN254 scalar field element
+    InvalidFieldElement,
 }
 
 impl From<DarksolError> for ProgramError {
@@ -58,6 +163,7 @@ impl From<DarksolError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+#[allow(deprecated)]
 impl<T> DecodeError<T> for DarksolError {
     fn type_of() -> &'static str {
         "DarksolError"
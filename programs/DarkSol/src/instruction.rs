@@ -1,7 +1,8 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{msg, program_error::ProgramError};
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
 
-use crate::{DepositRequest, TransferRequest, WithdrawRequest};
+use crate::config::ConfigParams;
+use crate::{DepositRequest, TransferRequest, WithdrawRequest, DEFAULT_ROOT_HISTORY_SIZE};
 
 // Instructions that our program can execute
 #[derive(BorshSerialize, BorshDeserialize,Debug)]
@@ -9,13 +10,33 @@ pub enum DarkSolInstruction {
     Deposit {request: DepositRequest},
     Transfer {request: TransferRequest},
     Withdraw {request: WithdrawRequest},
-    Initialize {},
+    Initialize {root_history_size: u32},
+    MigrateTree {tree_number: u64},
+    ExtendTree {tree_number: u64, additional_roots: u32},
+    Pause {operations: u8},
+    Unpause {operations: u8},
+    SetAdmin {new_admin: Pubkey},
+    UpdateConfig {params: ConfigParams},
+    SetFee {token_id: Vec<u8>, deposit_fee_bps: u16, withdraw_fee_bps: u16, treasury: Pubkey},
+    InitBuffer {buffer_id: u64, size: u32},
+    WriteBuffer {buffer_id: u64, offset: u32, data: Vec<u8>},
+    ExecuteFromBuffer {buffer_id: u64},
+    CloseBuffer {buffer_id: u64},
+    VerifyInclusion {tree_number: u64, leaf: [u8; 32], index: u64, path: Vec<[u8; 32]>, root: [u8; 32]},
+    MigrateNullifiers {tree_number: u64},
 }
 
 impl DarkSolInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
 
         msg!("Received Instruction: {:?}", input);
+        Self::unpack_staged(input)
+    }
+
+    // unpack_staged unpacks the instruction data without logging it, a
+    // request staged in a buffer is up to MAX_REQUEST_BUFFER_SIZE bytes
+    // and logging it burns the compute the large spend needs
+    pub fn unpack_staged(input: &[u8]) -> Result<Self, ProgramError> {
         // Get the instruction variant from the first byte
         let (&variant, rest) = input
             .split_first()
@@ -36,8 +57,68 @@ impl DarkSolInstruction {
                 Ok(Self::Withdraw { request })
             } 
             3 => {
-                Ok(Self::Initialize {})
+                // root history size is optional, fallback to the default size
+                let root_history_size = if rest.is_empty() {
+                    DEFAULT_ROOT_HISTORY_SIZE
+                } else {
+                    u32::try_from_slice(rest)?
+                };
+                Ok(Self::Initialize { root_history_size })
             } 
+            4 => {
+                let tree_number = u64::try_from_slice(rest)?;
+                Ok(Self::MigrateTree { tree_number })
+            }
+            5 => {
+                let (tree_number, additional_roots) = <(u64, u32)>::try_from_slice(rest)?;
+                Ok(Self::ExtendTree { tree_number, additional_roots })
+            }
+            6 => {
+                let operations = u8::try_from_slice(rest)?;
+                Ok(Self::Pause { operations })
+            }
+            7 => {
+                let operations = u8::try_from_slice(rest)?;
+                Ok(Self::Unpause { operations })
+            }
+            8 => {
+                let new_admin = Pubkey::try_from_slice(rest)?;
+                Ok(Self::SetAdmin { new_admin })
+            }
+            9 => {
+                let params = ConfigParams::try_from_slice(rest)?;
+                Ok(Self::UpdateConfig { params })
+            }
+            10 => {
+                let (token_id, deposit_fee_bps, withdraw_fee_bps, treasury) =
+                    <(Vec<u8>, u16, u16, Pubkey)>::try_from_slice(rest)?;
+                Ok(Self::SetFee { token_id, deposit_fee_bps, withdraw_fee_bps, treasury })
+            }
+            11 => {
+                let (buffer_id, size) = <(u64, u32)>::try_from_slice(rest)?;
+                Ok(Self::InitBuffer { buffer_id, size })
+            }
+            12 => {
+                let (buffer_id, offset, data) = <(u64, u32, Vec<u8>)>::try_from_slice(rest)?;
+                Ok(Self::WriteBuffer { buffer_id, offset, data })
+            }
+            13 => {
+                let buffer_id = u64::try_from_slice(rest)?;
+                Ok(Self::ExecuteFromBuffer { buffer_id })
+            }
+            14 => {
+                let buffer_id = u64::try_from_slice(rest)?;
+                Ok(Self::CloseBuffer { buffer_id })
+            }
+            15 => {
+                let (tree_number, leaf, index, path, root) =
+                    <(u64, [u8; 32], u64, Vec<[u8; 32]>, [u8; 32])>::try_from_slice(rest)?;
+                Ok(Self::VerifyInclusion { tree_number, leaf, index, path, root })
+            }
+            16 => {
+                let tree_number = u64::try_from_slice(rest)?;
+                Ok(Self::MigrateNullifiers { tree_number })
+            }
             _ => Err(ProgramError::InvalidInstructionData),
         }
     }
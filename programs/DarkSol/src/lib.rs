@@ -1,10 +1,15 @@
+pub mod accounts;
+pub mod buffer;
+pub mod config;
 pub mod entrypoint;
 pub mod error;
 pub mod instruction;
 pub mod merkle;
+pub mod nullifier;
 pub mod processor;
 pub mod state;
 pub mod utils;
+pub mod verifier;
 
 use merkle::sha256;
 
@@ -12,11 +17,22 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use primitive_types::U256;
 // use serde_wasm_bindgen::{from_value, to_value};
 use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
-use spl_token::{solana_program::program_pack::Pack, state::Account as TokenAccount};
+use sha3::{Digest, Keccak256};
+use spl_token_2022::{extension::StateWithExtensions, state::Account as TokenAccount};
 use std::clone;
 // use wasm_bindgen::prelude::*;
 
-const TREE_DEPTH: usize = 15;
+// TREE_DEPTH is the depth of newly created commitments trees, it must
+// match the depth the transfer and withdraw circuits are compiled with
+pub const TREE_DEPTH: usize = 16;
+
+// LEGACY_TREE_DEPTH is the depth of the trees created by the deployed
+// program, before the depth was stored in the commitments manager
+pub const LEGACY_TREE_DEPTH: usize = 15;
+
+// DEFAULT_ROOT_HISTORY_SIZE is the number of latest roots
+// a commitments tree accepts by default
+pub const DEFAULT_ROOT_HISTORY_SIZE: u32 = 100;
 
 pub const ZERO_VALUE: U256 = U256([
     0x30644E72E131A029,
@@ -36,6 +52,26 @@ pub fn derive_pda(value: u64, program_id: &Pubkey) -> (Pubkey, u8) {
     Pubkey::find_program_address(&[&seed], program_id)
 }
 
+// derive_native_vault_pda derives the program owned account
+// holding all shielded native SOL lamports
+pub fn derive_native_vault_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"native_vault"], program_id)
+}
+
+// derive_vault_pda derives the vault token account holding
+// all shielded funds of a single mint
+pub fn derive_vault_pda(mint: &Pubkey, token_program: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"vault", &mint.to_bytes(), &token_program.to_bytes()],
+        program_id,
+    )
+}
+
+// NATIVE_SOL_TOKEN_ID is the reserved token id of native SOL commitments.
+// It is the system program id which can never be a token mint, so native
+// SOL is never mistaken for wrapped SOL
+pub const NATIVE_SOL_TOKEN_ID: [u8; 32] = [0u8; 32];
+
 // PreCommitments contains info before being shielded inside protocol
 //#[wasm_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
@@ -57,6 +93,10 @@ impl PreCommitments {
         }
     }
 
+    pub fn is_native(&self) -> bool {
+        self.token_id.as_slice() == NATIVE_SOL_TOKEN_ID.as_slice()
+    }
+
     pub fn hash(&self) -> Vec<u8> {
         sha256(vec![
             self.utxo_pubkey.as_slice(),
@@ -87,11 +127,11 @@ pub struct ShieldCipherText {
 
 impl clone::Clone for ShieldCipherText {
     fn clone(&self) -> ShieldCipherText {
-        return ShieldCipherText {
+        ShieldCipherText {
             encrypted_text: self.encrypted_text.clone(),
             shield_key: self.shield_key.clone(),
             nonce: self.nonce.clone(),
-        };
+        }
     }
 }
 
@@ -154,9 +194,11 @@ impl DepositRequest {
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct DepositEvent {
     pub tree_number: u64,
-    pub start_position: u64,
+    pub start_position: u64, // leaf index of the deposited note
+    pub merkle_root: Vec<u8>, // tree root after the deposit
     pub pre_commitments: PreCommitments,
     pub shield_cipher_text: ShieldCipherText,
+    pub fee: u64, // deposit fee paid on top of the note value
 }
 
 // for js client support
@@ -166,14 +208,18 @@ impl DepositEvent {
     pub fn new(
         start_position: u64,
         tree_number: u64,
+        merkle_root: Vec<u8>,
         pre_commitments: PreCommitments,
         shield_cipher_text: ShieldCipherText,
+        fee: u64,
     ) -> Self {
         DepositEvent {
             start_position,
             tree_number,
+            merkle_root,
             pre_commitments,
             shield_cipher_text,
+            fee,
         }
     }
 
@@ -201,13 +247,13 @@ pub struct CommitmentCipherText {
 
 impl clone::Clone for CommitmentCipherText {
     fn clone(&self) -> CommitmentCipherText {
-        return CommitmentCipherText {
+        CommitmentCipherText {
             ciphertext: self.ciphertext.clone(),
             encrypted_sender_key: self.encrypted_sender_key.clone(),
             encrypted_receiver_key: self.encrypted_receiver_key.clone(),
             memo: self.memo.clone(),
             nonce: self.nonce.clone(),
-        };
+        }
     }
 }
 
@@ -247,11 +293,12 @@ impl CommitmentCipherText {
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct TransferRequest {
     proof: Vec<u8>,
-    merkle_root: Vec<u8>,
+    spent_trees: Vec<SpentTree>,         // tree and root of each spent UTXO, in the nullifiers order
     encrypted_commitments: Vec<Vec<u8>>, // list of newly generated commitments
     nullifiers: Vec<Vec<u8>>,            // nullifiers indicates spent UTXO
     metadata: RequestMetaData,
     commitment_cipher_text: Vec<CommitmentCipherText>,
+    relayer: Option<Relayer>, // set when a relayer submits the transfer
 }
 
 //#[wasm_bindgen]
@@ -259,17 +306,18 @@ impl TransferRequest {
     //#[wasm_bindgen(constructor)]
     pub fn new(
         proof: Vec<u8>,
-        merkle_root: Vec<u8>,
-        tree_number: u64,
+        tree_depth: u64,
+        circuit_id: u32,
         commitment_cipher_text: Vec<CommitmentCipherText>,
     ) -> Self {
         TransferRequest {
             proof,
-            merkle_root,
+            spent_trees: Vec::new(),
             encrypted_commitments: Vec::new(),
             nullifiers: Vec::new(),
-            metadata: RequestMetaData::new(tree_number),
+            metadata: RequestMetaData::new(tree_depth, circuit_id),
             commitment_cipher_text,
+            relayer: None,
         }
     }
 
@@ -288,24 +336,69 @@ impl TransferRequest {
         self.encrypted_commitments.push(value);
     }
 
+    // push_nullifiers adds a spent UTXO with the tree and root
+    // its merkle proof is generated against
     //#[wasm_bindgen]
-    pub fn push_nullifiers(&mut self, value: Vec<u8>) {
+    pub fn push_nullifiers(&mut self, value: Vec<u8>, tree_number: u64, merkle_root: Vec<u8>) {
         self.nullifiers.push(value);
+        self.spent_trees.push(SpentTree {
+            tree_number,
+            root: merkle_root,
+        });
     }
+
+    //#[wasm_bindgen]
+    pub fn set_relayer(&mut self, pubkey: Vec<u8>, fee: u64, token_id: Vec<u8>) {
+        self.relayer = Some(Relayer {
+            pubkey,
+            fee,
+            token_id,
+        });
+    }
+}
+
+// Relayer is the fee paying relayer submitting a spend on behalf of the
+// user so the user wallet is not linked to the spend. The proof commits
+// to it and the fee is paid to the relayer account out of the spent value
+//#[wasm_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Relayer {
+    pub pubkey: Vec<u8>,   // account receiving the fee, a token account of the token or a wallet for native SOL
+    pub fee: u64,
+    pub token_id: Vec<u8>, // token the fee is paid with
+}
+
+impl Relayer {
+    pub fn is_native(&self) -> bool {
+        self.token_id.as_slice() == NATIVE_SOL_TOKEN_ID.as_slice()
+    }
+}
+
+// SpentTree is the commitments tree and root a spent UTXO
+// is proven against, inputs can come from different trees
+//#[wasm_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct SpentTree {
+    pub tree_number: u64,
+    pub root: Vec<u8>,
 }
 
 //#[wasm_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct RequestMetaData {
-    tree_number: u64,
+    tree_depth: u64, // tree depth the proof is generated with
+    circuit_id: u32, // registered circuit version of the operation the proof is generated with
 }
 
 // for js client support
 //#[wasm_bindgen]
 impl RequestMetaData {
     //#[wasm_bindgen]
-    pub fn new(tree_number: u64) -> Self {
-        RequestMetaData { tree_number }
+    pub fn new(tree_depth: u64, circuit_id: u32) -> Self {
+        RequestMetaData {
+            tree_depth,
+            circuit_id,
+        }
     }
 
     //#[wasm_bindgen]
@@ -320,6 +413,18 @@ impl RequestMetaData {
     // }
 }
 
+// WithdrawEvent defines log after withdraw instruction
+//#[wasm_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct WithdrawEvent {
+    pub tree_number: u64,
+    pub start_position: u64,
+    pub commitments: Vec<Vec<u8>>,
+    pub commitment_cipher_text: Vec<CommitmentCipherText>,
+    pub amount: u64, // withdrawn note value, including the fee
+    pub fee: u64,    // withdraw fee paid to the treasury
+}
+
 // TransferEvent defines log after transfer instruction
 //#[wasm_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
@@ -367,12 +472,13 @@ impl TransactionEvent {
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct WithdrawRequest {
     proof: Vec<u8>,
-    merkle_root: Vec<u8>,
+    spent_trees: Vec<SpentTree>,   // tree and root of each spent UTXO, in the nullifiers order
     encrypted_commitments: Vec<Vec<u8>>, // list of newly generated commitment for the remain balance
     nullifiers: Vec<Vec<u8>>,      // nullifiers indicates spent UTXO
     metadata: RequestMetaData,
     pre_commitments: PreCommitments,
     commitment_cipher_texts: Vec<CommitmentCipherText>,
+    relayer: Option<Relayer>, // set when a relayer submits the withdraw
 }
 
 //#[wasm_bindgen]
@@ -380,20 +486,21 @@ impl WithdrawRequest {
     //#[wasm_bindgen(constructor)]
     pub fn new(
         proof: Vec<u8>,
-        merkle_root: Vec<u8>,
-        tree_number: u64,
+        tree_depth: u64,
+        circuit_id: u32,
         amount: u64,
         token_id: Vec<u8>,
         commitment_cipher_texts: Vec<CommitmentCipherText>,
     ) -> Self {
         WithdrawRequest {
             proof,
-            merkle_root,
+            spent_trees: Vec::new(),
             encrypted_commitments: Vec::new(),
             nullifiers: Vec::new(),
-            metadata: RequestMetaData::new(tree_number),
+            metadata: RequestMetaData::new(tree_depth, circuit_id),
             pre_commitments: PreCommitments::new(amount, token_id, Vec::new()), // no need to provide the encrypted value here
             commitment_cipher_texts,
+            relayer: None,
         }
     }
 
@@ -412,22 +519,99 @@ impl WithdrawRequest {
         self.encrypted_commitments.push(value);
     }
 
+    // push_nullifiers adds a spent UTXO with the tree and root
+    // its merkle proof is generated against
     //#[wasm_bindgen]
-    pub fn push_nullifiers(&mut self, value: Vec<u8>) {
+    pub fn push_nullifiers(&mut self, value: Vec<u8>, tree_number: u64, merkle_root: Vec<u8>) {
         self.nullifiers.push(value);
+        self.spent_trees.push(SpentTree {
+            tree_number,
+            root: merkle_root,
+        });
+    }
+
+    //#[wasm_bindgen]
+    pub fn set_relayer(&mut self, pubkey: Vec<u8>, fee: u64) {
+        self.relayer = Some(Relayer {
+            pubkey,
+            fee,
+            token_id: self.pre_commitments.token_id.clone(),
+        });
     }
 }
 
+// operation types bound into the proof params, also select the
+// circuits of the operation in the verification key registry
+pub const OPERATION_TRANSFER: u8 = 1;
+pub const OPERATION_WITHDRAW: u8 = 2;
+
+// BoundParams is the instruction context a spend proof is generated for.
+// Its hash is the params hash signed by the spender and committed in the
+// proof public values, so a proof can not be replayed with other trees,
+// operation, recipient, fee, relayer or program deployment. Solana has no
+// chain id, the program id identifies the deployment
+//#[wasm_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct BoundParams {
+    pub program_id: Vec<u8>,
+    pub tree_numbers: Vec<u64>, // spent tree of each input
+    pub operation: u8,
+    pub recipient: Vec<u8>, // empty for transfers, the output hashes bind the receivers
+    pub fee: u64,           // protocol fee, 0 for transfers
+    pub relayer: Option<Relayer>,
+}
+
+impl BoundParams {
+    // hash returns the keccak256 hash of the borsh serialized params
+    pub fn hash(&self) -> Result<Vec<u8>, std::io::Error> {
+        let serialized = borsh::to_vec(self)?;
+        Ok(Keccak256::digest(&serialized).to_vec())
+    }
+}
+
+// TransferPublicValue defines the public values committed by the transfer
+// circuit. The tree depth is committed so a proof generated for another
+// depth can not be verified against the tree, and the relayer so the fee
+// can not be redirected. The params hash binds the instruction context.
+// Each input is proven against the root of its own spent tree
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct TransferPublicValue {
+    pub spent_trees: Vec<SpentTree>,
+    pub params_hash: Vec<u8>,
+    pub nullifiers: Vec<Vec<u8>>,
+    pub output_hashes: Vec<Vec<u8>>,
+    pub tree_depth: u64,
+    pub relayer: Option<Relayer>,
+}
+
+// WithdrawPublicValue defines the public values committed by the withdraw
+// circuit. Besides the transfer public values it binds the withdrawn
+// amount, the fee taken from it, token and recipient token account
+// ( or wallet for native SOL )
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct WithdrawPublicValue {
+    pub spent_trees: Vec<SpentTree>,
+    pub params_hash: Vec<u8>,
+    pub nullifiers: Vec<Vec<u8>>,
+    pub output_hashes: Vec<Vec<u8>>,
+    pub tree_depth: u64,
+    pub amount: u64,
+    pub fee: u64,
+    pub token_id: Vec<u8>,
+    pub recipient: Vec<u8>,
+    pub relayer: Option<Relayer>,
+}
+
 pub fn fetch_mint_address(token_account: &AccountInfo) -> Result<String, ProgramError> {
     let token_data = token_account.try_borrow_data()?;
-    let token_account = TokenAccount::unpack(&token_data)?;
+    let token_account = StateWithExtensions::<TokenAccount>::unpack(&token_data)?.base;
 
     Ok(token_account.mint.to_string())
 }
 
 // NullifierEvent defines log after adding new nullifers instruction
 //#[wasm_bindgen]
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
 pub struct NullifierEvent {
     pub nullifiers: Vec<Vec<u8>>,
 }
@@ -458,9 +642,15 @@ impl NullifierEvent {
     }
 }
 
+// VERIFY_INSTRUCTION is the variant of the verification program
+// instruction verifying a proof
+pub const VERIFY_INSTRUCTION: u8 = 0;
+
 /// The instruction data for the program.
-#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
 pub struct SP1Groth16Proof {
+    pub operation: u8,   // operation the circuit proves
+    pub circuit_id: u32, // registered circuit version of the operation the proof targets
     pub proof: Vec<u8>,
     pub sp1_public_inputs: Vec<u8>,
 }
\ No newline at end of file
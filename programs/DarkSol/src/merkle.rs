@@ -1,7 +1,11 @@
-use crate::{u256_to_bytes, utils::serialize::BorshSerializeWithLength, PreCommitments, ZERO_VALUE};
+use crate::error::DarksolError;
+use crate::{u256_to_bytes, PreCommitments};
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::msg;
+use bytemuck::{Pod, Zeroable};
+use primitive_types::U256;
+use solana_program::{msg, program_error::ProgramError};
 use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 
 pub fn sha256(inputs: Vec<&[u8]>) -> Vec<u8> {
     solana_sha256_hasher::hashv(&inputs).to_bytes().to_vec()
@@ -23,106 +27,725 @@ pub fn hash_precommits(pre_commitments: PreCommitments) -> Vec<u8> {
     ])
 }
 
-// pub fn poseidon(inputs: Vec<&[u8]>) -> Vec<u8> {
-//     let inputs = inputs
-//         .iter()
-//         .map(|input| {
-//             let mut bytes = [0u8; 32];
-//             if input.len() < 32 {
-//                 // fill from the last index
-//                 let start = 32 - input.len();
-//                 bytes[start..].copy_from_slice(&input[..]);
-//             } else {
-//                 bytes.copy_from_slice(input);
-//             };
-//             bytes
-//         })
-//         .collect::<Vec<[u8; 32]>>();
-//     Vec::from(
-//         solana_poseidon::hashv(
-//             solana_poseidon::Parameters::Bn254X5,
-//             solana_poseidon::Endianness::BigEndian,
-//             &inputs.iter().map(|v| v.as_slice()).collect::<Vec<&[u8]>>(),
-//         )
-//         .unwrap()
-//         .to_bytes(),
-//     )
-// }
+// MAX_TREE_DEPTH is the deepest tree the commitments account layout can store
+pub const MAX_TREE_DEPTH: usize = 32;
+
+// ZEROS are the roots of empty subtrees at each level. ZEROS[0] is the
+// ZERO_VALUE leaf and ZEROS[level + 1] = hash_left_right(ZEROS[level], ZEROS[level])
+pub const ZEROS: [[u8; 32]; MAX_TREE_DEPTH + 1] = [
+    [
+        0x1a, 0x01, 0x11, 0xea, 0x39, 0x7f, 0xe6, 0x9a,
+        0x28, 0x33, 0xe8, 0x48, 0x79, 0xb9, 0x70, 0x91,
+        0xb8, 0x50, 0x45, 0xb6, 0x81, 0x81, 0x58, 0x5d,
+        0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29,
+    ],
+    [
+        0xf4, 0x4d, 0x20, 0xb0, 0x60, 0xe9, 0xc5, 0x4e,
+        0x96, 0x7b, 0x03, 0x3c, 0x06, 0xe3, 0xb4, 0x80,
+        0x22, 0xdf, 0x7e, 0xe7, 0x2c, 0x8f, 0x5d, 0x38,
+        0x98, 0xcf, 0x77, 0x92, 0x40, 0x6b, 0x3a, 0x00,
+    ],
+    [
+        0xd3, 0x34, 0x64, 0x1a, 0x0c, 0x76, 0x0b, 0x54,
+        0x36, 0xd8, 0xc1, 0x89, 0xa7, 0x6d, 0xcd, 0x3b,
+        0xdb, 0xc6, 0x83, 0xe6, 0xe8, 0x31, 0x90, 0xbe,
+        0xb2, 0x4c, 0x82, 0xe3, 0x8b, 0x91, 0x26, 0x15,
+    ],
+    [
+        0xb8, 0xb2, 0x80, 0x3a, 0x5c, 0x42, 0xed, 0x08,
+        0x89, 0xb8, 0x94, 0x58, 0xe7, 0xbb, 0x70, 0x38,
+        0x1a, 0x1a, 0x26, 0x05, 0xff, 0x52, 0x5e, 0x11,
+        0x37, 0x5c, 0x28, 0x05, 0x68, 0xa4, 0x5c, 0x84,
+    ],
+    [
+        0x65, 0x39, 0xb2, 0x01, 0x60, 0x74, 0x0b, 0x6f,
+        0x10, 0xeb, 0x5e, 0xcc, 0x59, 0x06, 0x3d, 0x4e,
+        0xf3, 0x0f, 0x10, 0x98, 0x14, 0x29, 0x7e, 0x56,
+        0xe8, 0x83, 0x06, 0x11, 0xc9, 0x81, 0x8a, 0x9e,
+    ],
+    [
+        0xb0, 0x41, 0x5e, 0x69, 0x3a, 0xa5, 0x18, 0xfa,
+        0x27, 0x27, 0xfc, 0xde, 0x6b, 0xa1, 0xc4, 0x81,
+        0x95, 0xd3, 0x90, 0x7f, 0xc0, 0x12, 0xd8, 0x88,
+        0x18, 0xa0, 0x3f, 0x4b, 0xc7, 0x2e, 0x0a, 0x63,
+    ],
+    [
+        0xeb, 0xd3, 0xf4, 0xfb, 0x71, 0x60, 0xc6, 0x3a,
+        0x53, 0x54, 0x2b, 0xad, 0x65, 0xd1, 0x69, 0xf9,
+        0x61, 0xc3, 0x36, 0x3a, 0xb8, 0x11, 0x1d, 0x36,
+        0x79, 0x5c, 0x3b, 0xa3, 0x29, 0x59, 0xb3, 0x82,
+    ],
+    [
+        0xb4, 0xcc, 0x26, 0x65, 0x95, 0x09, 0xd0, 0x34,
+        0xa9, 0xb7, 0xe1, 0x54, 0x40, 0xb8, 0xa2, 0xd7,
+        0xc2, 0x21, 0x7a, 0xa2, 0x4d, 0xe7, 0x37, 0x5e,
+        0xc8, 0x20, 0xf7, 0x97, 0x18, 0xe0, 0x7d, 0x74,
+    ],
+    [
+        0x0d, 0x87, 0x5b, 0x42, 0xa4, 0x60, 0x6c, 0x99,
+        0x61, 0xbd, 0xb0, 0xf4, 0xea, 0xc1, 0x03, 0x6e,
+        0x3f, 0x5a, 0x8a, 0xb2, 0x62, 0x96, 0xd7, 0x68,
+        0xc7, 0x90, 0xab, 0x16, 0xa2, 0xf6, 0x68, 0x42,
+    ],
+    [
+        0x7e, 0x80, 0x05, 0xe5, 0xce, 0x83, 0x2b, 0xda,
+        0x5b, 0x97, 0xa2, 0x41, 0xdd, 0x80, 0xae, 0xe2,
+        0x8d, 0xf3, 0xd9, 0x3c, 0x29, 0x4f, 0xf9, 0x19,
+        0xc9, 0xd3, 0x8d, 0x63, 0xcf, 0xaf, 0xfe, 0x3e,
+    ],
+    [
+        0xc2, 0x24, 0x07, 0x17, 0xd1, 0x57, 0x07, 0x56,
+        0x8a, 0x3f, 0x6f, 0x9a, 0x48, 0xa2, 0x88, 0x6c,
+        0xb9, 0xf0, 0x37, 0xc2, 0x89, 0x8e, 0xdc, 0x20,
+        0x84, 0x3f, 0x4e, 0xf4, 0x0d, 0x3a, 0x5c, 0x75,
+    ],
+    [
+        0xaa, 0x36, 0xeb, 0x17, 0xb3, 0x31, 0xea, 0x5e,
+        0xd4, 0x56, 0x17, 0x51, 0xdb, 0x00, 0x0a, 0x0e,
+        0x48, 0x83, 0x65, 0xba, 0xf4, 0x1f, 0x66, 0x88,
+        0x77, 0xda, 0x84, 0x2c, 0x5b, 0x09, 0xe5, 0xeb,
+    ],
+    [
+        0x61, 0x2f, 0x85, 0xbf, 0x63, 0x47, 0x88, 0xe1,
+        0x1d, 0xba, 0x9b, 0x1f, 0x81, 0xde, 0xc1, 0xb2,
+        0x3f, 0xe9, 0xb5, 0xd7, 0xaf, 0xfc, 0xdd, 0xa1,
+        0x20, 0x38, 0xe3, 0x5d, 0xf6, 0x62, 0x00, 0x67,
+    ],
+    [
+        0x2c, 0xed, 0x9c, 0xda, 0x4a, 0x64, 0xe9, 0x8a,
+        0x81, 0x15, 0x29, 0x8f, 0xe8, 0xf5, 0x45, 0x0e,
+        0xd0, 0x90, 0x5e, 0x94, 0x89, 0xd2, 0xc1, 0x0e,
+        0xd4, 0x7c, 0xd7, 0x95, 0xff, 0x2c, 0xff, 0x32,
+    ],
+    [
+        0x9b, 0xbd, 0xf3, 0x3b, 0x33, 0xb8, 0x7e, 0x2e,
+        0xc5, 0xa4, 0x2d, 0xa8, 0xbc, 0xa5, 0x1e, 0x58,
+        0x21, 0x65, 0x45, 0x19, 0xab, 0xe0, 0xa2, 0xa3,
+        0xdb, 0xd0, 0x89, 0x6a, 0x20, 0xc1, 0x20, 0xe0,
+    ],
+    [
+        0x60, 0xb5, 0x70, 0xb6, 0xc8, 0xfe, 0xa6, 0x87,
+        0xef, 0x05, 0x7f, 0xfd, 0xd2, 0xe1, 0xb0, 0x9a,
+        0xc0, 0x90, 0x63, 0x3d, 0x58, 0xe0, 0x53, 0x40,
+        0x48, 0x06, 0x0e, 0x54, 0x89, 0x27, 0xf1, 0xb1,
+    ],
+    [
+        0x74, 0x1f, 0x65, 0x61, 0xfb, 0x81, 0x6b, 0xff,
+        0x42, 0x47, 0x4d, 0x93, 0x00, 0x2b, 0x8f, 0x7c,
+        0xa2, 0x0d, 0x37, 0xa9, 0xb4, 0x70, 0x83, 0x5f,
+        0x3a, 0xfc, 0x52, 0xd6, 0x0f, 0xc2, 0x96, 0xd7,
+    ],
+    [
+        0x5b, 0xac, 0xd5, 0x3b, 0x6b, 0xf7, 0x88, 0xcb,
+        0x2e, 0xec, 0x17, 0x25, 0x9b, 0x3e, 0x19, 0x5f,
+        0x0f, 0xa2, 0xce, 0xde, 0x25, 0x83, 0x26, 0x69,
+        0x09, 0x0c, 0x94, 0x86, 0x4d, 0xb1, 0x3b, 0x96,
+    ],
+    [
+        0xdf, 0x3f, 0x7e, 0x03, 0xfe, 0xa9, 0x3e, 0x64,
+        0xf8, 0x4e, 0x87, 0x99, 0x99, 0xb3, 0x6d, 0x03,
+        0xad, 0xef, 0x25, 0x89, 0xb1, 0x90, 0x73, 0x2f,
+        0x86, 0xe7, 0xa3, 0x8d, 0xf5, 0xd4, 0x2f, 0xa1,
+    ],
+    [
+        0x22, 0x36, 0x43, 0x4a, 0xe2, 0x0a, 0x52, 0x07,
+        0x61, 0x06, 0x02, 0x07, 0xfe, 0x68, 0x8e, 0x9d,
+        0xe7, 0xb3, 0x93, 0x7d, 0xa5, 0x4d, 0x51, 0xaf,
+        0xd0, 0x83, 0xb8, 0xbc, 0x05, 0xd7, 0xf7, 0x47,
+    ],
+    [
+        0xb8, 0xfc, 0x36, 0xd9, 0x21, 0x52, 0xcf, 0x2e,
+        0xaa, 0x11, 0x20, 0xaa, 0x7b, 0x9c, 0xda, 0xc7,
+        0x9c, 0x7a, 0x6d, 0xfe, 0xd0, 0x7e, 0x94, 0xeb,
+        0xd1, 0x43, 0x1f, 0x99, 0xd6, 0xd5, 0xee, 0x23,
+    ],
+    [
+        0xb0, 0x70, 0x8d, 0xc5, 0xa0, 0x6f, 0xc5, 0x0f,
+        0x47, 0xd9, 0xbc, 0x10, 0x14, 0x1a, 0xd3, 0x65,
+        0xb1, 0x03, 0x04, 0x3b, 0xbb, 0xf6, 0xb7, 0xfa,
+        0xa9, 0xae, 0x7d, 0xc2, 0x7d, 0xbb, 0x32, 0x2c,
+    ],
+    [
+        0x99, 0x4d, 0xb0, 0x89, 0xd6, 0xaf, 0x65, 0x86,
+        0x51, 0x37, 0x00, 0x53, 0x4c, 0xa2, 0x3c, 0x03,
+        0xb0, 0xf2, 0x62, 0xb1, 0x35, 0x6c, 0xb3, 0xef,
+        0xac, 0x4d, 0xd3, 0xee, 0xe1, 0x9d, 0x73, 0x76,
+    ],
+    [
+        0x70, 0x37, 0x9f, 0x41, 0x38, 0x76, 0x7a, 0x94,
+        0x32, 0x3d, 0x2e, 0xff, 0xbb, 0xeb, 0x90, 0xa1,
+        0x18, 0x73, 0x11, 0xf4, 0x47, 0xb4, 0xd9, 0x71,
+        0x5c, 0xd6, 0xf3, 0xc3, 0x62, 0x47, 0x1b, 0xe6,
+    ],
+    [
+        0x00, 0x22, 0x23, 0x1b, 0x3f, 0x41, 0xc0, 0x73,
+        0xd8, 0x9d, 0x34, 0xa9, 0x43, 0x96, 0x02, 0xf9,
+        0x00, 0x79, 0xd5, 0xc5, 0x53, 0xe7, 0xc1, 0x7b,
+        0xbe, 0xdc, 0x1c, 0x8f, 0x07, 0xb8, 0xdd, 0xb9,
+    ],
+    [
+        0xb9, 0x12, 0xa3, 0x4a, 0x07, 0xd4, 0x28, 0xd5,
+        0xeb, 0x29, 0x9f, 0x38, 0x9f, 0x79, 0xff, 0x77,
+        0xfe, 0xaa, 0x70, 0x23, 0xb8, 0x44, 0xbb, 0x12,
+        0xeb, 0xc8, 0x43, 0x86, 0xd0, 0x59, 0x07, 0x4c,
+    ],
+    [
+        0xa6, 0xe9, 0x9e, 0x11, 0x0d, 0xa2, 0xf2, 0x54,
+        0x92, 0xc7, 0xa0, 0x3d, 0x11, 0xea, 0x36, 0x3a,
+        0xe5, 0x92, 0xad, 0xbe, 0x4d, 0xf2, 0x33, 0x9a,
+        0x40, 0xe0, 0x25, 0x43, 0x71, 0xc6, 0x56, 0xd2,
+    ],
+    [
+        0xcb, 0x7b, 0xba, 0x9e, 0xf7, 0xf2, 0x4e, 0xcd,
+        0x53, 0xd5, 0xba, 0x58, 0x41, 0x22, 0x7b, 0x74,
+        0xae, 0x68, 0x2e, 0x7a, 0x0f, 0x6a, 0x10, 0x5f,
+        0xdf, 0xae, 0x11, 0xb2, 0xcf, 0xca, 0x29, 0xa3,
+    ],
+    [
+        0xd3, 0xbd, 0x12, 0x1f, 0xa3, 0x9c, 0x47, 0xdb,
+        0xfc, 0xc9, 0x07, 0xed, 0x5f, 0x73, 0xd8, 0xcb,
+        0x84, 0xeb, 0x90, 0x89, 0xb1, 0x32, 0x2a, 0xbf,
+        0xcb, 0x72, 0xb8, 0x85, 0x51, 0x7d, 0xef, 0xb6,
+    ],
+    [
+        0x80, 0x59, 0x69, 0x4a, 0x71, 0x1e, 0x44, 0xbe,
+        0x9a, 0x0c, 0x6d, 0xfd, 0x6a, 0x61, 0x11, 0x99,
+        0xc2, 0xa3, 0x09, 0x6b, 0x09, 0x39, 0xe0, 0x0a,
+        0xa5, 0x87, 0x9d, 0x3d, 0xa7, 0xb1, 0x6d, 0x91,
+    ],
+    [
+        0xce, 0xbd, 0x0e, 0xf1, 0x58, 0xea, 0xe0, 0x58,
+        0x54, 0x19, 0x45, 0xed, 0x00, 0xd1, 0x4a, 0x10,
+        0xf7, 0x7e, 0x5b, 0x43, 0x5b, 0x2d, 0xfc, 0x91,
+        0xa1, 0x5c, 0xd2, 0x57, 0x0b, 0x37, 0xec, 0x4e,
+    ],
+    [
+        0x89, 0x36, 0x56, 0x0e, 0x55, 0x6b, 0x84, 0x3c,
+        0x7a, 0x42, 0xad, 0x3d, 0x38, 0xd8, 0x56, 0x25,
+        0xb3, 0xe7, 0xf2, 0x92, 0x5f, 0x6c, 0x97, 0x81,
+        0xb3, 0x72, 0xaf, 0xf2, 0x2e, 0x1a, 0x72, 0xb8,
+    ],
+    [
+        0x2d, 0xa4, 0xe3, 0x36, 0xe8, 0x7f, 0x53, 0x93,
+        0x42, 0xb7, 0xa8, 0x92, 0x04, 0x1f, 0x58, 0x91,
+        0xaf, 0x22, 0x26, 0x6a, 0xa1, 0xc6, 0x6b, 0x20,
+        0xd5, 0x6c, 0xe9, 0x2d, 0x44, 0xa1, 0x0a, 0x00,
+    ],
+];
+
+// BN254_SCALAR_MODULUS is the big endian modulus of the field Poseidon
+// BN254 hashes over
+const BN254_SCALAR_MODULUS: [u8; 32] = [
+    0x30, 0x64, 0x4e, 0x72, 0xe1, 0x31, 0xa0, 0x29,
+    0xb8, 0x50, 0x45, 0xb6, 0x81, 0x81, 0x58, 0x5d,
+    0x28, 0x33, 0xe8, 0x48, 0x79, 0xb9, 0x70, 0x91,
+    0x43, 0xe1, 0xf5, 0x93, 0xf0, 0x00, 0x00, 0x01,
+];
+
+// HashFunction is the hash a commitments tree is built with. It is
+// recorded in the tree account so trees built with different hash
+// functions can be used side by side
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HashFunction {
+    Sha256 = 0,
+    PoseidonBn254 = 1,
+}
+
+impl TryFrom<u8> for HashFunction {
+    type Error = ProgramError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(HashFunction::Sha256),
+            1 => Ok(HashFunction::PoseidonBn254),
+            _ => Err(DarksolError::UnsupportedHashFunction.into()),
+        }
+    }
+}
+
+impl HashFunction {
+    // hash_precommits hashes the note into the leaf inserted in a tree
+    // built with this hash function
+    pub fn hash_precommits(self, pre_commitments: &PreCommitments) -> Result<[u8; 32], ProgramError> {
+        match self {
+            HashFunction::Sha256 => Sha256Hasher::hash_precommits(pre_commitments),
+            HashFunction::PoseidonBn254 => PoseidonHasher::hash_precommits(pre_commitments),
+        }
+    }
+
+    // check_leaves ensures the leaves given by a client can be inserted
+    // in a tree built with this hash function
+    pub fn check_leaves(self, leaves: &[[u8; 32]]) -> Result<(), ProgramError> {
+        match self {
+            HashFunction::Sha256 => leaves.iter().try_for_each(Sha256Hasher::check_leaf),
+            HashFunction::PoseidonBn254 => leaves.iter().try_for_each(PoseidonHasher::check_leaf),
+        }
+    }
+}
+
+// MerkleHasher hashes the leaves and nodes of a commitments tree
+pub trait MerkleHasher {
+    // hashv hashes the inputs into a single node
+    fn hashv(inputs: &[&[u8]]) -> Result<[u8; 32], ProgramError>;
+
+    // zero_leaf is the value of the leaves not inserted yet
+    fn zero_leaf() -> [u8; 32];
+
+    fn hash_left_right(left: &[u8], right: &[u8]) -> Result<[u8; 32], ProgramError> {
+        Self::hashv(&[left, right])
+    }
+
+    // check_leaf ensures a leaf given by a client is a valid node
+    fn check_leaf(_leaf: &[u8; 32]) -> Result<(), ProgramError> {
+        Ok(())
+    }
+
+    // hash_precommits hashes the note into its leaf
+    fn hash_precommits(pre_commitments: &PreCommitments) -> Result<[u8; 32], ProgramError> {
+        Self::hashv(&[
+            pre_commitments.utxo_pubkey.as_slice(),
+            pre_commitments.token_id.as_slice(),
+            pre_commitments.value.to_le_bytes().as_slice(),
+        ])
+    }
+
+    // zeros returns the roots of empty subtrees from the leaves up to
+    // `tree_depth`, zeros[level + 1] = hash_left_right(zeros[level], zeros[level])
+    fn zeros(tree_depth: usize) -> Result<Vec<[u8; 32]>, ProgramError> {
+        let mut zeros = Vec::with_capacity(tree_depth + 1);
+        zeros.push(Self::zero_leaf());
+        for level in 0..tree_depth {
+            let zero = zeros[level];
+            zeros.push(Self::hash_left_right(&zero, &zero)?);
+        }
+
+        Ok(zeros)
+    }
+}
+
+// Sha256Hasher builds the trees with SHA-256, the default hash function
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    fn hashv(inputs: &[&[u8]]) -> Result<[u8; 32], ProgramError> {
+        Ok(solana_sha256_hasher::hashv(inputs).to_bytes())
+    }
+
+    fn zero_leaf() -> [u8; 32] {
+        ZEROS[0]
+    }
+
+    // the empty subtree roots are precomputed
+    fn zeros(tree_depth: usize) -> Result<Vec<[u8; 32]>, ProgramError> {
+        ZEROS
+            .get(..=tree_depth)
+            .map(|zeros| zeros.to_vec())
+            .ok_or(DarksolError::InvalidCommitmentsAccount.into())
+    }
+}
+
+// PoseidonHasher builds the trees with Poseidon over the BN254 scalar
+// field, every node is a field element which is much cheaper to hash in
+// the spend circuits than SHA-256
+pub struct PoseidonHasher;
+
+impl MerkleHasher for PoseidonHasher {
+    fn hashv(inputs: &[&[u8]]) -> Result<[u8; 32], ProgramError> {
+        let inputs = inputs
+            .iter()
+            .map(|input| to_field_element(input))
+            .collect::<Result<Vec<[u8; 32]>, ProgramError>>()?;
+
+        solana_poseidon::hashv(
+            solana_poseidon::Parameters::Bn254X5,
+            solana_poseidon::Endianness::BigEndian,
+            &inputs.iter().map(|input| input.as_slice()).collect::<Vec<&[u8]>>(),
+        )
+        .map(|hash| hash.to_bytes())
+        .map_err(|_| DarksolError::FailedCreateCommitmentHash.into())
+    }
+
+    // ZERO_VALUE is below the field modulus so it is also the empty
+    // leaf of Poseidon trees
+    fn zero_leaf() -> [u8; 32] {
+        ZEROS[0]
+    }
+
+    // leaves are outputs of Poseidon so they must be field elements
+    fn check_leaf(leaf: &[u8; 32]) -> Result<(), ProgramError> {
+        to_field_element(leaf).map(|_| ())
+    }
+
+    // the utxo public key must be a field element, the token id is a
+    // public key so it is hashed as its two 16 bytes halves. The amount
+    // is a big endian field element like the other inputs
+    fn hash_precommits(pre_commitments: &PreCommitments) -> Result<[u8; 32], ProgramError> {
+        let token_id = pre_commitments.token_id.as_slice();
+        if token_id.len() != 32 {
+            return Err(DarksolError::InvalidTokenId.into());
+        }
+
+        Self::hashv(&[
+            pre_commitments.utxo_pubkey.as_slice(),
+            &token_id[..16],
+            &token_id[16..],
+            pre_commitments.value.to_be_bytes().as_slice(),
+        ])
+    }
+}
+
+// verify_merkle_path checks `leaf` is the leaf at `index` of the tree with
+// root `root`. `path` holds the sibling of each level from the leaves up,
+// siblings in empty subtrees are the zero values of the hasher
+pub fn verify_merkle_path<H: MerkleHasher>(
+    leaf: &[u8; 32],
+    index: u64,
+    path: &[[u8; 32]],
+    root: &[u8; 32],
+) -> Result<bool, ProgramError> {
+    if path.len() > MAX_TREE_DEPTH || index >> path.len() != 0 {
+        return Ok(false);
+    }
+    // nodes which can not be in the tree, like values over the Poseidon
+    // field modulus, never prove an inclusion
+    if H::check_leaf(leaf).is_err() || path.iter().any(|sibling| H::check_leaf(sibling).is_err()) {
+        return Ok(false);
+    }
+
+    let mut node = *leaf;
+    for (level, sibling) in path.iter().enumerate() {
+        node = if (index >> level) & 1 == 0 {
+            H::hash_left_right(&node, sibling)?
+        } else {
+            H::hash_left_right(sibling, &node)?
+        };
+    }
+
+    Ok(&node == root)
+}
+
+// leaves_from_bytes checks every commitment is a 32 bytes leaf
+pub fn leaves_from_bytes(commitments: &[Vec<u8>]) -> Result<Vec<[u8; 32]>, ProgramError> {
+    commitments
+        .iter()
+        .map(|commitment| {
+            <[u8; 32]>::try_from(commitment.as_slice())
+                .map_err(|_| DarksolError::FailedInsertCommitmentHash.into())
+        })
+        .collect()
+}
+
+// to_field_element reads a big endian input of up to 32 bytes as a BN254
+// scalar field element. Inputs over the modulus are refused instead of
+// reduced, otherwise two inputs would hash to the same node
+fn to_field_element(input: &[u8]) -> Result<[u8; 32], ProgramError> {
+    if input.len() > 32 {
+        return Err(DarksolError::FailedCreateCommitmentHash.into());
+    }
+
+    let value = U256::from_big_endian(input);
+    if value >= U256::from_big_endian(&BN254_SCALAR_MODULUS) {
+        return Err(DarksolError::InvalidFieldElement.into());
+    }
+    Ok(u256_to_bytes(value))
+}
+
+// COMMITMENTS_ACCOUNT_TAG marks the zero-copy commitments account layout. The
+// legacy borsh layout starts with a u64 data length so it never matches the tag
+pub const COMMITMENTS_ACCOUNT_TAG: [u8; 8] = *b"veiltree";
+
+// COMMITMENTS_ACCOUNT_VERSION is the current commitments account layout version
+pub const COMMITMENTS_ACCOUNT_VERSION: u8 = 1;
+
+
+// CommitmentsHeader is the fixed size part of the commitments account, all
+// fields are byte arrays so the header can be read from unaligned account data
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct CommitmentsHeader {
+    tag: [u8; 8],
+    version: u8,
+    tree_depth: u8,
+    hash_function: u8,
+    tree_number: [u8; 8],
+    next_leaf_index: [u8; 8],
+    root_history_size: [u8; 4],  // root history capacity
+    root_history_index: [u8; 4], // next slot to overwrite in root history
+    merkle_root: [u8; 32],
+    filled_sub_trees: [[u8; 32]; MAX_TREE_DEPTH],
+}
+
+pub const COMMITMENTS_HEADER_LENGTH: usize = std::mem::size_of::<CommitmentsHeader>();
+
+// commitments_account_space returns the account space of a tree
+// accepting the latest `root_history_size` roots
+pub fn commitments_account_space(root_history_size: usize) -> usize {
+    COMMITMENTS_HEADER_LENGTH + root_history_size * 32
+}
+
+impl CommitmentsHeader {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        self.tree_depth as usize
+    }
+
+    pub fn hash_function(&self) -> Result<HashFunction, ProgramError> {
+        HashFunction::try_from(self.hash_function)
+    }
+
+    pub fn tree_number(&self) -> u64 {
+        u64::from_le_bytes(self.tree_number)
+    }
+
+    pub fn next_leaf_index(&self) -> u64 {
+        u64::from_le_bytes(self.next_leaf_index)
+    }
+
+    pub fn root_history_size(&self) -> usize {
+        u32::from_le_bytes(self.root_history_size) as usize
+    }
+
+    fn root_history_index(&self) -> usize {
+        u32::from_le_bytes(self.root_history_index) as usize
+    }
+}
+
+// InsertResult describes a batch of commitments inserted in a tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InsertResult {
+    pub first_index: u64,   // leaf index of the first inserted commitment
+    pub count: u64,         // number of inserted commitments
+    pub new_root: [u8; 32], // tree root after the insertion
+}
 
 // Batch Incremental Merkle Tree for commitments
 // each account store a single tree indicate by its
-// tree number
-#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
-pub struct CommitmentsAccount<const TREE_DEPTH: usize> {
-    pub next_leaf_index: usize,
-    merkle_root: Vec<u8>,
-    new_tree_root: Vec<u8>,
-    tree_number: u64,
-    zeros: Vec<Vec<u8>>,
-    filled_sub_trees: Vec<Vec<u8>>,
-    root_history: HashMap<Vec<u8>, bool>, // root -> seen
-    pub nullifiers: HashMap<Vec<u8>, bool>,
+// tree number. The tree is read and updated in place on
+// the account data: a CommitmentsHeader followed by the
+// root history circular buffer
+pub struct CommitmentsAccount<D> {
+    data: D,
+}
+
+impl CommitmentsAccount<Vec<u8>> {
+    /// Create a new empty Merkle Tree of depth `tree_depth` in memory
+    /// which accepts the latest `root_history_size` roots
+    pub fn new(tree_number: u64, tree_depth: usize, root_history_size: usize) -> Self {
+        let root_history_size = root_history_size.max(1);
+        let data = vec![0u8; commitments_account_space(root_history_size)];
+        Self::initialize(data, tree_number, tree_depth, root_history_size, HashFunction::Sha256).unwrap()
+    }
 }
 
-impl<const TREE_DEPTH: usize> CommitmentsAccount<TREE_DEPTH> {
-    /// Create a new empty Merkle Tree
-    pub fn new(tree_number: u64) -> Self {
-        let zero_value = u256_to_bytes(ZERO_VALUE).to_vec();
-        let mut root_history: HashMap<Vec<u8>, bool> = HashMap::new();
-        let mut zeros: Vec<Vec<u8>> = Vec::with_capacity(TREE_DEPTH);
-        let mut filled_sub_trees: Vec<Vec<u8>> = Vec::with_capacity(TREE_DEPTH);
+impl<D: Deref<Target = [u8]>> CommitmentsAccount<D> {
+    /// Load the tree stored in the account data without copying it
+    pub fn load(data: D) -> Result<Self, ProgramError> {
+        // legacy borsh accounts have no tag, they are refused until migrated
+        if data.len() < COMMITMENTS_ACCOUNT_TAG.len()
+            || data[..COMMITMENTS_ACCOUNT_TAG.len()] != COMMITMENTS_ACCOUNT_TAG
+        {
+            return Err(DarksolError::TreeNotMigrated.into());
+        }
+        if data.len() < COMMITMENTS_HEADER_LENGTH {
+            return Err(DarksolError::InvalidCommitmentsAccount.into());
+        }
+
+        let header: &CommitmentsHeader =
+            bytemuck::from_bytes(&data[..COMMITMENTS_HEADER_LENGTH]);
+        if header.version != COMMITMENTS_ACCOUNT_VERSION {
+            return Err(DarksolError::UnsupportedAccountVersion.into());
+        }
+        if header.tree_depth() == 0
+            || header.tree_depth() > MAX_TREE_DEPTH
+            || header.hash_function().is_err()
+            || header.root_history_size() == 0
+            || data.len() < commitments_account_space(header.root_history_size())
+        {
+            return Err(DarksolError::InvalidCommitmentsAccount.into());
+        }
+
+        Ok(Self { data })
+    }
+
+    pub fn header(&self) -> &CommitmentsHeader {
+        bytemuck::from_bytes(&self.data[..COMMITMENTS_HEADER_LENGTH])
+    }
+
+    fn root_history(&self) -> &[[u8; 32]] {
+        let size = self.header().root_history_size();
+        bytemuck::cast_slice(&self.data[COMMITMENTS_HEADER_LENGTH..commitments_account_space(size)])
+    }
+
+    pub fn tree_number(&self) -> u64 {
+        self.header().tree_number()
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        self.header().tree_depth()
+    }
+
+    pub fn hash_function(&self) -> Result<HashFunction, ProgramError> {
+        self.header().hash_function()
+    }
+
+    pub fn next_leaf_index(&self) -> u64 {
+        self.header().next_leaf_index()
+    }
+
+    pub fn exceed_tree_depth(&self, commitments_length: usize) -> bool {
+        let base: usize = 2; // an explicit type is required
+                             // if exceeding max tree depth create a new tree
+        commitments_length + self.next_leaf_index() as usize > base.pow(self.tree_depth() as u32)
+    }
+
+    /// Get the Merkle root
+    pub fn root(&self) -> Vec<u8> {
+        self.header().merkle_root.to_vec()
+    }
 
-        let mut current_zero = zero_value.clone();
-        for _ in 0..TREE_DEPTH {
-            // Push it to zeros array
-            zeros.push(current_zero.clone());
+    /// Check if the root is one of the latest roots in root history
+    pub fn has_root(&self, root: &[u8]) -> bool {
+        self.root_history()
+            .iter()
+            .any(|history_root| history_root.as_slice() == root)
+    }
 
-            filled_sub_trees.push(current_zero.clone());
+    /// Get the root history capacity
+    pub fn root_history_size(&self) -> usize {
+        self.header().root_history_size()
+    }
 
-            // Calculate the zero value for this level
-            current_zero = hash_left_right(&current_zero, &current_zero);
+    /// Check `leaf` is the inserted leaf at `index` under `root`, one of
+    /// the roots in root history, with the hash function of the tree
+    pub fn verify_inclusion(
+        &self,
+        leaf: &[u8; 32],
+        index: u64,
+        path: &[[u8; 32]],
+        root: &[u8; 32],
+    ) -> Result<bool, ProgramError> {
+        if path.len() != self.tree_depth() || index >= self.next_leaf_index() || !self.has_root(root) {
+            return Ok(false);
         }
 
-        // Now safely insert into the inner HashMap
-        root_history.insert(current_zero.clone(), true);
+        match self.hash_function()? {
+            HashFunction::Sha256 => verify_merkle_path::<Sha256Hasher>(leaf, index, path, root),
+            HashFunction::PoseidonBn254 => verify_merkle_path::<PoseidonHasher>(leaf, index, path, root),
+        }
+    }
+}
 
-        Self {
-            next_leaf_index: 0,
-            merkle_root: current_zero.clone(),
-            new_tree_root: current_zero.clone(),
-            tree_number,
-            zeros,
-            filled_sub_trees,
-            root_history,
-            nullifiers: HashMap::new(),
+impl<D: DerefMut<Target = [u8]>> CommitmentsAccount<D> {
+    /// Write a new empty Merkle Tree of depth `tree_depth` hashed with
+    /// `hash_function` to the account data which accepts the latest
+    /// `root_history_size` roots
+    pub fn initialize(
+        mut data: D,
+        tree_number: u64,
+        tree_depth: usize,
+        root_history_size: usize,
+        hash_function: HashFunction,
+    ) -> Result<Self, ProgramError> {
+        if tree_depth == 0
+            || tree_depth > MAX_TREE_DEPTH
+            || root_history_size == 0
+            || data.len() < commitments_account_space(root_history_size)
+        {
+            return Err(DarksolError::InvalidCommitmentsAccount.into());
+        }
+        let zeros = match hash_function {
+            HashFunction::Sha256 => Sha256Hasher::zeros(tree_depth)?,
+            HashFunction::PoseidonBn254 => PoseidonHasher::zeros(tree_depth)?,
+        };
+        data.fill(0);
+
+        let header: &mut CommitmentsHeader =
+            bytemuck::from_bytes_mut(&mut data[..COMMITMENTS_HEADER_LENGTH]);
+        header.tag = COMMITMENTS_ACCOUNT_TAG;
+        header.version = COMMITMENTS_ACCOUNT_VERSION;
+        header.tree_depth = tree_depth as u8;
+        header.hash_function = hash_function as u8;
+        header.tree_number = tree_number.to_le_bytes();
+        header.root_history_size = (root_history_size as u32).to_le_bytes();
+        header.merkle_root = zeros[tree_depth];
+        header.filled_sub_trees[..tree_depth].copy_from_slice(&zeros[..tree_depth]);
+
+        let mut tree = Self { data };
+
+        // fill the root history with the empty tree root
+        let (_, root_history) = tree.split_mut();
+        root_history.fill(zeros[tree_depth]);
+
+        Ok(tree)
+    }
+
+    // split_mut borrows the header and the root history at the same time
+    fn split_mut(&mut self) -> (&mut CommitmentsHeader, &mut [[u8; 32]]) {
+        let (header, rest) = self.data.split_at_mut(COMMITMENTS_HEADER_LENGTH);
+        let header: &mut CommitmentsHeader = bytemuck::from_bytes_mut(header);
+        let size = header.root_history_size();
+        (header, bytemuck::cast_slice_mut(&mut rest[..size * 32]))
+    }
+
+    /// Batch insert multiple commitments, hashed with the hash function
+    /// of the tree. The commitments are left untouched
+    pub fn insert_commitments(&mut self, commitments: &[[u8; 32]]) -> Result<InsertResult, String> {
+        match self.hash_function() {
+            Ok(HashFunction::Sha256) => self.insert_commitments_with::<Sha256Hasher>(commitments),
+            Ok(HashFunction::PoseidonBn254) => self.insert_commitments_with::<PoseidonHasher>(commitments),
+            Err(_) => Err("unsupported hash function".to_string()),
         }
     }
 
-    /// Batch insert multiple commitments
-    pub fn insert_commitments(
+    fn insert_commitments_with<H: MerkleHasher>(
         &mut self,
-        commitments: &mut Vec<Vec<u8>>,
-        write_to: &mut &mut [u8],
-    ) -> Result<u64, String> {
+        commitments: &[[u8; 32]],
+    ) -> Result<InsertResult, String> {
         // this check is just double check to make sure the leaf count does not exceed the limit
         // as above logic must also check this in order to create another data account
         // for a new tree if insertion exceeds the max tree dept.
         let mut count = commitments.len();
         msg!("count: {}", count);
         if self.exceed_tree_depth(count) {
-            return Err(format!("exceed max tree dept"));
+            return Err("exceed max tree dept".to_string());
         }
 
-        let mut level_insertion_index: usize = self.next_leaf_index;
+        // refuse the whole insertion before the tree is updated
+        for commitment in commitments {
+            H::check_leaf(commitment).map_err(|err| err.to_string())?;
+        }
+
+        let first_index = self.next_leaf_index();
+        if count == 0 {
+            return Ok(InsertResult {
+                first_index,
+                count: 0,
+                new_root: self.header().merkle_root,
+            });
+        }
+
+        // each level hashes are written over the lower level ones
+        let mut hashes = commitments.to_vec();
+
+        // Poseidon trees compute the empty subtree roots on the fly
+        let zeros = H::zeros(self.tree_depth()).map_err(|err| err.to_string())?;
+
+        let (header, root_history) = self.split_mut();
+
+        let mut level_insertion_index: usize = header.next_leaf_index() as usize;
 
-        self.next_leaf_index += count;
+        header.next_leaf_index = (first_index + count as u64).to_le_bytes();
 
         // Variables for starting point at next tree level
         let mut next_level_hash_index: usize = 0;
         let mut next_level_start_index: usize;
 
         // Loop through each level of the merkle tree and update
-        for level in 0..TREE_DEPTH {
+        for (level, zero) in zeros.iter().enumerate().take(header.tree_depth()) {
             // Calculate the index to start at for the next level
             // >> is equivalent to / 2 rounded down
             next_level_start_index = level_insertion_index >> 1;
@@ -136,10 +759,9 @@ impl<const TREE_DEPTH: usize> CommitmentsAccount<TREE_DEPTH> {
                 next_level_hash_index = (level_insertion_index >> 1) - next_level_start_index;
 
                 // Calculate the hash for the next level
-                commitments[next_level_hash_index] = hash_left_right(
-                    &self.filled_sub_trees[level],
-                    &commitments[insertion_element],
-                );
+                hashes[next_level_hash_index] =
+                    H::hash_left_right(&header.filled_sub_trees[level], &hashes[insertion_element])
+                        .map_err(|err| err.to_string())?;
 
                 // Increment
                 insertion_element += 1;
@@ -148,18 +770,16 @@ impl<const TREE_DEPTH: usize> CommitmentsAccount<TREE_DEPTH> {
 
             // We'll always be on the left side now
             for insertion_element in (insertion_element..count).step_by(2) {
-                let &mut right: &mut Vec<u8>;
-
                 // Calculate right value
-                if insertion_element < count - 1 {
-                    right = commitments[insertion_element + 1].clone();
+                let right = if insertion_element < count - 1 {
+                    hashes[insertion_element + 1]
                 } else {
-                    right = self.zeros[level].clone();
-                }
+                    *zero
+                };
 
                 // If we've created a new subtree at this level, update
                 if insertion_element == count - 1 || insertion_element == count - 2 {
-                    self.filled_sub_trees[level] = commitments[insertion_element].clone();
+                    header.filled_sub_trees[level] = hashes[insertion_element];
                 }
 
                 // Calculate index to insert hash into leafHashes[]
@@ -167,8 +787,8 @@ impl<const TREE_DEPTH: usize> CommitmentsAccount<TREE_DEPTH> {
                 next_level_hash_index = (level_insertion_index >> 1) - next_level_start_index;
 
                 // Calculate the hash for the next level
-                commitments[next_level_hash_index] =
-                    hash_left_right(&commitments[insertion_element], &right);
+                hashes[next_level_hash_index] = H::hash_left_right(&hashes[insertion_element], &right)
+                    .map_err(|err| err.to_string())?;
 
                 // Increment level insertion index
                 level_insertion_index += 2;
@@ -181,70 +801,241 @@ impl<const TREE_DEPTH: usize> CommitmentsAccount<TREE_DEPTH> {
             count = next_level_hash_index + 1;
         }
 
-        // Update the Merkle tree root
-        self.merkle_root = commitments[0].clone();
-        self.root_history.insert(self.merkle_root.clone(), true);
+        // Update the Merkle tree root and overwrite the oldest root in root history
+        header.merkle_root = hashes[0];
+        let index = header.root_history_index() % root_history.len();
+        root_history[index] = header.merkle_root;
+        header.root_history_index = (((index + 1) % root_history.len()) as u32).to_le_bytes();
+
+        Ok(InsertResult {
+            first_index,
+            count: commitments.len() as u64,
+            new_root: header.merkle_root,
+        })
+    }
 
-        if !write_to.is_empty() {
-            self.serialize_with_length(write_to)
-            .map_err(|e| format!("Failed to serialize: {}", e))?;
+    /// Grow the root history capacity by `additional_roots`, the account
+    /// data must already be resized to the new space
+    pub fn extend_root_history(&mut self, additional_roots: usize) -> Result<(), ProgramError> {
+        let root_history_size = self.root_history_size();
+        let new_root_history_size = root_history_size + additional_roots;
+        if additional_roots == 0
+            || new_root_history_size > u32::MAX as usize
+            || self.data.len() < commitments_account_space(new_root_history_size)
+        {
+            return Err(DarksolError::InvalidCommitmentsAccount.into());
         }
 
-        Ok(self.next_leaf_index as u64)
+        // order the current roots from the oldest to the newest
+        let (header, root_history) = self.split_mut();
+        root_history.rotate_left(header.root_history_index() % root_history_size);
+        header.root_history_size = (new_root_history_size as u32).to_le_bytes();
+        header.root_history_index = (root_history_size as u32).to_le_bytes();
+
+        // new slots hold the current root until they are overwritten
+        let (header, root_history) = self.split_mut();
+        root_history[root_history_size..].fill(header.merkle_root);
+
+        Ok(())
     }
 
-    pub fn exceed_tree_depth(&self, commitments_length: usize) -> bool {
-        let base: usize = 2; // an explicit type is required
-                             // if exceeding max tree depth create a new tree
-        if commitments_length + self.next_leaf_index > base.pow(TREE_DEPTH as u32) {
-            return true;
+    /// Write the legacy borsh tree to the account data in the current
+    /// layout with a root history of `root_history_size` roots, at least
+    /// `legacy_root_history_size` so every legacy root is carried over
+    pub fn migrate(
+        data: D,
+        legacy: &LegacyCommitmentsAccount,
+        root_history_size: usize,
+    ) -> Result<Self, ProgramError> {
+        // legacy trees store one filled subtree per level
+        let tree_depth = legacy.filled_sub_trees.len();
+        if legacy.merkle_root.len() != 32
+            || legacy.filled_sub_trees.iter().any(|node| node.len() != 32)
+            || legacy.root_history.keys().any(|root| root.len() != 32)
+            || root_history_size < legacy_root_history_size(legacy)
+        {
+            return Err(DarksolError::InvalidCommitmentsAccount.into());
         }
 
-        return false;
-    }
+        // legacy trees are SHA-256 trees
+        let mut tree = Self::initialize(
+            data,
+            legacy.tree_number,
+            tree_depth,
+            root_history_size,
+            HashFunction::Sha256,
+        )?;
 
-    /// Get the Merkle root
-    pub fn root(&self) -> Vec<u8> {
-        self.merkle_root.clone()
-    }
+        let (header, root_history) = tree.split_mut();
+        header.next_leaf_index = (legacy.next_leaf_index as u64).to_le_bytes();
+        header.merkle_root.copy_from_slice(&legacy.merkle_root);
+        for (node, legacy_node) in header.filled_sub_trees.iter_mut().zip(legacy.filled_sub_trees.iter()) {
+            node.copy_from_slice(legacy_node);
+        }
 
-    /// Get the Merkle root
-    pub fn has_root(&self, root: &[u8]) -> bool {
-        self.root_history.contains_key(root)
-    }
+        // the legacy root history keeps every root of the tree unordered,
+        // all of them are carried over so no proof against a legacy root is
+        // invalidated. Their order is unknown so the next insertions evict
+        // them in byte order, the latest root last
+        let mut legacy_roots: Vec<&Vec<u8>> = legacy
+            .root_history
+            .keys()
+            .filter(|root| **root != legacy.merkle_root)
+            .collect();
+        legacy_roots.sort();
+        for (slot, root) in root_history.iter_mut().zip(legacy_roots.iter()) {
+            slot.copy_from_slice(root);
+        }
+        root_history[legacy_roots.len()..].fill(header.merkle_root);
+        let latest_root_index = legacy_roots.len();
+        header.root_history_index = (((latest_root_index + 1) % root_history.len()) as u32).to_le_bytes();
 
-    pub fn insert_nullifier(&mut self, nullifier: Vec<u8>) {
-        self.nullifiers.insert(nullifier, true);
+        Ok(tree)
     }
+}
 
-    pub fn insert_nullifiers(&mut self, nullifiers: HashMap<Vec<u8>, bool>) {
-        self.nullifiers.extend(nullifiers);
-    }
+// legacy_root_history_size returns the number of roots a legacy tree
+// holds, its latest root included
+pub fn legacy_root_history_size(legacy: &LegacyCommitmentsAccount) -> usize {
+    let latest_root_seen = legacy.root_history.contains_key(&legacy.merkle_root);
+    legacy.root_history.len() + usize::from(!latest_root_seen)
+}
 
-    pub fn check_nullifier(&self, nullifier: &Vec<u8>) -> bool {
-        self.nullifiers.contains_key(nullifier)
-    }
+// LegacyCommitmentsAccount is the borsh layout commitments accounts were
+// deployed with before the zero-copy layout, only used for migration
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct LegacyCommitmentsAccount {
+    pub next_leaf_index: usize,
+    pub merkle_root: Vec<u8>,
+    pub new_tree_root: Vec<u8>,
+    pub tree_number: u64,
+    pub zeros: Vec<Vec<u8>>,
+    pub filled_sub_trees: Vec<Vec<u8>>,
+    pub root_history: HashMap<Vec<u8>, bool>, // root -> seen
+    pub nullifiers: HashMap<Vec<u8>, bool>,   // nullifiers spent against the tree
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::serialize::{BorshDeserializeWithLength, BorshSerializeWithLength};
+    use crate::state::CommitmentsManagerAccount;
+    use crate::{u256_to_bytes, LEGACY_TREE_DEPTH, ZERO_VALUE};
+
+    fn leaf(i: u8) -> [u8; 32] {
+        Sha256Hasher::hashv(&[&[i]]).unwrap()
+    }
 
     #[test]
-    fn test_zero_tree() {
-        let zero_value = u256_to_bytes(ZERO_VALUE).to_vec();
-        const TREE_DEPTH: usize = 8;
-        let zero_tree = CommitmentsAccount::<TREE_DEPTH>::new(0);
-        let mut level_zero = zero_value.clone();
-        for i in 0..TREE_DEPTH {
-            assert_eq!(zero_tree.zeros[i], level_zero);
-            assert_eq!(zero_tree.filled_sub_trees[i], level_zero);
+    fn test_zeros() {
+        let mut level_zero = u256_to_bytes(ZERO_VALUE).to_vec();
+        for zero in &ZEROS {
+            assert_eq!(zero.to_vec(), level_zero);
 
             level_zero = hash_left_right(&level_zero, &level_zero);
         }
+    }
+
+    #[test]
+    fn test_hasher_zeros() {
+        assert_eq!(Sha256Hasher::zeros(MAX_TREE_DEPTH).unwrap(), ZEROS.to_vec());
+
+        let zeros = PoseidonHasher::zeros(MAX_TREE_DEPTH).unwrap();
+        assert_eq!(zeros[0], ZEROS[0]);
+        for level in 0..MAX_TREE_DEPTH {
+            assert_eq!(
+                zeros[level + 1],
+                PoseidonHasher::hash_left_right(&zeros[level], &zeros[level]).unwrap()
+            );
+            assert_ne!(zeros[level + 1], ZEROS[level + 1]);
+        }
+    }
+
+    #[test]
+    fn test_poseidon_tree() {
+        const TREE_DEPTH: usize = 5;
+        let poseidon_leaf = |i: u8| PoseidonHasher::hashv(&[&[i]]).unwrap();
+
+        // the root does not depend on how the leaves are batched
+        let mut root_lists = vec![];
+        for gap in 1..10 {
+            let mut data = vec![0u8; commitments_account_space(4)];
+            let mut tree =
+                CommitmentsAccount::initialize(&mut data[..], 0, TREE_DEPTH, 4, HashFunction::PoseidonBn254)
+                    .unwrap();
+            assert_eq!(tree.hash_function().unwrap(), HashFunction::PoseidonBn254);
+
+            let leaves: Vec<[u8; 32]> = (0..16).map(poseidon_leaf).collect();
+            for batch in leaves.chunks(gap) {
+                tree.insert_commitments(batch).unwrap();
+            }
+            assert_eq!(tree.next_leaf_index(), 16);
+            root_lists.push(tree.root());
+        }
+        for i in 0..root_lists.len() - 1 {
+            assert_eq!(root_lists[i], root_lists[i + 1]);
+        }
+
+        // the same leaves give another root in a SHA-256 tree
+        let mut sha256_tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
+        let leaves: Vec<[u8; 32]> = (0..16).map(poseidon_leaf).collect();
+        sha256_tree.insert_commitments(&leaves).unwrap();
+        assert_ne!(sha256_tree.root(), root_lists[0]);
+
+        // leaves over the modulus are refused and the tree is left untouched
+        let mut data = vec![0u8; commitments_account_space(4)];
+        let mut tree =
+            CommitmentsAccount::initialize(&mut data[..], 0, TREE_DEPTH, 4, HashFunction::PoseidonBn254)
+                .unwrap();
+        let invalid_leaves = [poseidon_leaf(0), BN254_SCALAR_MODULUS];
+        assert_eq!(
+            HashFunction::PoseidonBn254.check_leaves(&invalid_leaves),
+            Err(DarksolError::InvalidFieldElement.into())
+        );
+        assert!(HashFunction::Sha256.check_leaves(&invalid_leaves).is_ok());
+        assert!(tree.insert_commitments(&invalid_leaves).is_err());
+        assert_eq!(tree.next_leaf_index(), 0);
+        assert_eq!(tree.root(), PoseidonHasher::zeros(TREE_DEPTH).unwrap()[TREE_DEPTH]);
+    }
+
+    #[test]
+    fn test_hash_precommits() {
+        let pre_commitments = PreCommitments::new(1_000, vec![0xff; 32], vec![0x0e; 32]);
+        assert_eq!(
+            HashFunction::Sha256.hash_precommits(&pre_commitments).unwrap().to_vec(),
+            hash_precommits(pre_commitments.clone())
+        );
+
+        // Poseidon leaves are field elements, the token id is hashed in
+        // halves so any public key can be shielded
+        let leaf = HashFunction::PoseidonBn254.hash_precommits(&pre_commitments).unwrap();
+        assert!(leaf.as_slice() < BN254_SCALAR_MODULUS.as_slice());
+        assert!(HashFunction::try_from(2).is_err());
+
+        // utxo public keys over the modulus are refused instead of reduced
+        let mut utxo_pubkey = BN254_SCALAR_MODULUS.to_vec();
+        let over_modulus = PreCommitments::new(1_000, vec![0xff; 32], utxo_pubkey.clone());
+        assert_eq!(
+            HashFunction::PoseidonBn254.hash_precommits(&over_modulus),
+            Err(DarksolError::InvalidFieldElement.into())
+        );
+        utxo_pubkey[31] -= 1;
+        let below_modulus = PreCommitments::new(1_000, vec![0xff; 32], utxo_pubkey);
+        assert!(HashFunction::PoseidonBn254.hash_precommits(&below_modulus).is_ok());
+    }
+
+    #[test]
+    fn test_zero_tree() {
+        const TREE_DEPTH: usize = 8;
+        let zero_tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
+        for (filled_sub_tree, zero) in zero_tree.header().filled_sub_trees.iter().zip(&ZEROS[..TREE_DEPTH]) {
+            assert_eq!(filled_sub_tree, zero);
+        }
 
-        assert_eq!(zero_tree.merkle_root, level_zero);
-        assert!(zero_tree.root_history.contains_key(&level_zero));
+        assert_eq!(zero_tree.root(), ZEROS[TREE_DEPTH].to_vec());
+        assert!(zero_tree.has_root(&ZEROS[TREE_DEPTH]));
+        assert_eq!(zero_tree.root_history_size(), 4);
+        assert_eq!(zero_tree.header().version(), COMMITMENTS_ACCOUNT_VERSION);
     }
 
     #[test]
@@ -254,29 +1045,28 @@ mod tests {
         let mut gap = 1;
         let mut root_lists = vec![];
         while gap < 10 {
-            let mut tree = CommitmentsAccount::<TREE_DEPTH>::new(0);
+            let mut tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
             let root = tree.root();
 
-            let mut empty_writer: &mut [u8] = &mut[];
             for step in 0..(16 / gap) {
                 let mut insert_list = vec![];
                 for i in (step * gap)..((step + 1) * gap) {
-                    let hash_i = sha256(vec![&[i]]);
+                    let hash_i = leaf(i);
                     insert_list.push(hash_i);
                 }
 
-                tree.insert_commitments(&mut insert_list, &mut empty_writer).unwrap();
+                tree.insert_commitments(&insert_list).unwrap();
             }
 
             for i in ((16 / gap) * gap)..16 {
-                let hash_i = sha256(vec![&[i]]);
-                let mut insert_list = vec![hash_i];
-                tree.insert_commitments(&mut insert_list, &mut empty_writer).unwrap();
+                let hash_i = leaf(i);
+                let insert_list = vec![hash_i];
+                tree.insert_commitments(&insert_list).unwrap();
             }
 
             gap += 1;
             assert_ne!(root, tree.root());
-            assert_eq!(tree.next_leaf_index, 16);
+            assert_eq!(tree.next_leaf_index(), 16);
             root_lists.push(tree.root());
         }
 
@@ -285,18 +1075,306 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_insert_result() {
+        const TREE_DEPTH: usize = 3;
+        let mut tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
+
+        let empty = tree.insert_commitments(&[]).unwrap();
+        assert_eq!(empty, InsertResult { first_index: 0, count: 0, new_root: ZEROS[TREE_DEPTH] });
+
+        // two inserts filling the tree, the second one starting after the first
+        let leaves: Vec<[u8; 32]> = (0..8).map(leaf).collect();
+        for batch in [&leaves[..3], &leaves[3..]] {
+            let prior_next_leaf_index = tree.next_leaf_index();
+            let result = tree.insert_commitments(batch).unwrap();
+            let inserted = prior_next_leaf_index as usize + batch.len();
+
+            assert_eq!(result.first_index, prior_next_leaf_index);
+            assert_eq!(result.count, batch.len() as u64);
+            assert_eq!(result.new_root.to_vec(), tree.root());
+            assert_eq!(result.new_root, merkle_root::<Sha256Hasher>(&leaves[..inserted], TREE_DEPTH));
+            assert_eq!(tree.next_leaf_index(), inserted as u64);
+        }
+
+        // a full tree refuses the insertion and is left untouched
+        let full_root = tree.header().merkle_root;
+        assert!(tree.exceed_tree_depth(1));
+        assert!(tree.insert_commitments(&[leaf(8)]).is_err());
+        assert_eq!(tree.next_leaf_index(), 8);
+        assert_eq!(tree.header().merkle_root, full_root);
+
+        // the commitments roll over to the first leaf of the next tree
+        let mut next_tree = CommitmentsAccount::new(1, TREE_DEPTH, 4);
+        let rolled_over = [leaf(8), leaf(9)];
+        let result = next_tree.insert_commitments(&rolled_over).unwrap();
+        assert_eq!(result.first_index, 0);
+        assert_eq!(result.count, 2);
+        assert_eq!(result.new_root.to_vec(), next_tree.root());
+        assert_eq!(result.new_root, merkle_root::<Sha256Hasher>(&rolled_over, TREE_DEPTH));
+    }
+
+    // merkle_root computes the root of a tree from all its leaves
+    fn merkle_root<H: MerkleHasher>(leaves: &[[u8; 32]], tree_depth: usize) -> [u8; 32] {
+        let zeros = H::zeros(tree_depth).unwrap();
+        let mut level_nodes = leaves.to_vec();
+        for zero in zeros.iter().take(tree_depth) {
+            level_nodes = level_nodes
+                .chunks(2)
+                .map(|pair| H::hash_left_right(&pair[0], pair.get(1).unwrap_or(zero)).unwrap())
+                .collect();
+        }
+        level_nodes.first().copied().unwrap_or(zeros[tree_depth])
+    }
+
+    // merkle_path computes the path of the leaf at `index` from all the leaves
+    fn merkle_path<H: MerkleHasher>(leaves: &[[u8; 32]], index: usize, tree_depth: usize) -> Vec<[u8; 32]> {
+        let zeros = H::zeros(tree_depth).unwrap();
+        let mut level_nodes = leaves.to_vec();
+        let mut path = vec![];
+        for (level, zero) in zeros.iter().enumerate().take(tree_depth) {
+            let sibling = (index >> level) ^ 1;
+            path.push(level_nodes.get(sibling).copied().unwrap_or(*zero));
+
+            level_nodes = level_nodes
+                .chunks(2)
+                .map(|pair| H::hash_left_right(&pair[0], pair.get(1).unwrap_or(zero)).unwrap())
+                .collect();
+        }
+        path
+    }
+
+    #[test]
+    fn test_verify_inclusion() {
+        const TREE_DEPTH: usize = 5;
+        let leaves: Vec<[u8; 32]> = (0..11).map(leaf).collect();
+
+        let mut tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
+        tree.insert_commitments(&leaves[..7]).unwrap();
+        let old_root = tree.header().merkle_root;
+        tree.insert_commitments(&leaves[7..]).unwrap();
+        let root = tree.header().merkle_root;
+
+        for (index, leaf) in leaves.iter().enumerate() {
+            let path = merkle_path::<Sha256Hasher>(&leaves, index, TREE_DEPTH);
+            assert!(verify_merkle_path::<Sha256Hasher>(leaf, index as u64, &path, &root).unwrap());
+            assert!(tree.verify_inclusion(leaf, index as u64, &path, &root).unwrap());
+
+            // another index, leaf or path does not verify
+            assert!(!tree.verify_inclusion(leaf, index as u64 ^ 1, &path, &root).unwrap());
+            assert!(!tree.verify_inclusion(&leaves[(index + 1) % 11], index as u64, &path, &root).unwrap());
+            assert!(!tree.verify_inclusion(leaf, index as u64, &path[1..], &root).unwrap());
+        }
+
+        // leaves are also proven against the older roots in root history
+        let path = merkle_path::<Sha256Hasher>(&leaves[..7], 3, TREE_DEPTH);
+        assert!(tree.verify_inclusion(&leaves[3], 3, &path, &old_root).unwrap());
+        assert!(!tree.verify_inclusion(&leaves[3], 3, &path, &[0u8; 32]).unwrap());
+
+        // leaves not inserted yet are rejected
+        let path = merkle_path::<Sha256Hasher>(&leaves, 11, TREE_DEPTH);
+        assert!(!tree.verify_inclusion(&ZEROS[0], 11, &path, &root).unwrap());
+
+        // Poseidon trees are proven with Poseidon paths
+        let mut data = vec![0u8; commitments_account_space(4)];
+        let mut poseidon_tree =
+            CommitmentsAccount::initialize(&mut data[..], 0, TREE_DEPTH, 4, HashFunction::PoseidonBn254).unwrap();
+        let leaves: Vec<[u8; 32]> = (0..5u8).map(|i| PoseidonHasher::hashv(&[&[i]]).unwrap()).collect();
+        let root = poseidon_tree.insert_commitments(&leaves).unwrap().new_root;
+        let path = merkle_path::<PoseidonHasher>(&leaves, 4, TREE_DEPTH);
+        assert!(poseidon_tree.verify_inclusion(&leaves[4], 4, &path, &root).unwrap());
+        let path = merkle_path::<Sha256Hasher>(&leaves, 4, TREE_DEPTH);
+        assert!(!poseidon_tree.verify_inclusion(&leaves[4], 4, &path, &root).unwrap());
+    }
+
     #[test]
     fn test_exceed_tree() {
         const TREE_DEPTH: usize = 5;
-        let mut tree = CommitmentsAccount::<TREE_DEPTH>::new(0);
+        let mut tree = CommitmentsAccount::new(0, TREE_DEPTH, 4);
         let mut insert_list = vec![];
         for i in 0..33 {
-            let hash_i = sha256(vec![&[i]]);
+            let hash_i = leaf(i);
             insert_list.push(hash_i);
         }
 
-        let mut empty_writer: &mut [u8] = &mut[];
-        let result = tree.insert_commitments(&mut insert_list, &mut empty_writer);
+        let result = tree.insert_commitments(&insert_list);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_root_history_bounded() {
+        const TREE_DEPTH: usize = 5;
+        const ROOT_HISTORY_SIZE: usize = 4;
+        let mut tree = CommitmentsAccount::new(0, TREE_DEPTH, ROOT_HISTORY_SIZE);
+        let empty_root = tree.root();
+
+        let mut roots = vec![];
+        for i in 0..10 {
+            let insert_list = vec![leaf(i)];
+            tree.insert_commitments(&insert_list).unwrap();
+            roots.push(tree.root());
+        }
+
+        // only the latest roots are accepted
+        assert!(!tree.has_root(&empty_root));
+        for (i, root) in roots.iter().enumerate() {
+            assert_eq!(tree.has_root(root), i >= roots.len() - ROOT_HISTORY_SIZE);
+        }
+
+        // account size does not grow with insertions
+        assert_eq!(tree.data.len(), commitments_account_space(ROOT_HISTORY_SIZE));
+    }
+
+    #[test]
+    fn test_extend_root_history() {
+        const TREE_DEPTH: usize = 5;
+        let mut data = vec![0u8; commitments_account_space(6)];
+        let mut tree =
+            CommitmentsAccount::initialize(
+                &mut data[..commitments_account_space(3)],
+                0,
+                TREE_DEPTH,
+                3,
+                HashFunction::Sha256,
+            )
+            .unwrap();
+
+        let mut roots = vec![];
+        for i in 0..5 {
+            tree.insert_commitments(&[leaf(i)]).unwrap();
+            roots.push(tree.root());
+        }
+
+        let mut tree = CommitmentsAccount::load(&mut data[..]).unwrap();
+        tree.extend_root_history(3).unwrap();
+        assert_eq!(tree.root_history_size(), 6);
+
+        // the latest roots are kept and the newer roots overwrite the oldest first
+        for i in 5..8 {
+            tree.insert_commitments(&[leaf(i)]).unwrap();
+            roots.push(tree.root());
+        }
+        for (i, root) in roots.iter().enumerate() {
+            assert_eq!(tree.has_root(root), i >= 2);
+        }
+
+        tree.insert_commitments(&[leaf(8)]).unwrap();
+        assert!(!tree.has_root(&roots[2]));
+        assert!(tree.has_root(&roots[3]));
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        const TREE_DEPTH: usize = 5;
+        let mut tree = CommitmentsAccount::new(3, TREE_DEPTH, 16);
+        for i in 0..6 {
+            tree.insert_commitments(&[leaf(i)]).unwrap();
+        }
+
+        let header = *tree.header();
+        let legacy = LegacyCommitmentsAccount {
+            next_leaf_index: header.next_leaf_index() as usize,
+            merkle_root: tree.root(),
+            new_tree_root: ZEROS[TREE_DEPTH].to_vec(),
+            tree_number: header.tree_number(),
+            zeros: ZEROS[..TREE_DEPTH].iter().map(|zero| zero.to_vec()).collect(),
+            filled_sub_trees: header.filled_sub_trees[..TREE_DEPTH]
+                .iter()
+                .map(|node| node.to_vec())
+                .collect(),
+            root_history: tree
+                .root_history()
+                .iter()
+                .map(|root| (root.to_vec(), true))
+                .collect(),
+            nullifiers: HashMap::new(),
+        };
+
+        // the deployed layout is length prefixed borsh
+        let legacy_data = legacy.try_to_vec_with_length().unwrap();
+        let legacy = LegacyCommitmentsAccount::try_from_slice_with_length(&legacy_data).unwrap();
+        // unmigrated trees are refused
+        assert_eq!(
+            CommitmentsAccount::load(&legacy_data[..]).err(),
+            Some(DarksolError::TreeNotMigrated.into())
+        );
+
+        // every legacy root must fit in the root history
+        let legacy_roots: Vec<[u8; 32]> = tree.root_history().to_vec();
+        assert_eq!(legacy_root_history_size(&legacy), 7);
+        let mut data = vec![0u8; commitments_account_space(6)];
+        assert!(CommitmentsAccount::migrate(&mut data[..], &legacy, 6).is_err());
+
+        let mut data = vec![0u8; commitments_account_space(8)];
+        let mut migrated = CommitmentsAccount::migrate(&mut data[..], &legacy, 8).unwrap();
+        assert_eq!(migrated.tree_number(), 3);
+        assert_eq!(migrated.tree_depth(), TREE_DEPTH);
+        assert_eq!(migrated.next_leaf_index(), 6);
+        assert_eq!(migrated.root(), tree.root());
+        assert_eq!(migrated.root_history_size(), 8);
+
+        // every legacy root is carried over, the empty tree root included
+        assert!(legacy_roots.iter().all(|root| migrated.has_root(root)));
+        assert!(migrated.has_root(&ZEROS[TREE_DEPTH]));
+
+        // the latest legacy root is the last one evicted
+        let mut evicting = CommitmentsAccount::migrate(vec![0u8; commitments_account_space(8)], &legacy, 8).unwrap();
+        for i in 0..7 {
+            evicting.insert_commitments(&[leaf(100 + i)]).unwrap();
+        }
+        assert!(evicting.has_root(&tree.header().merkle_root));
+        assert_eq!(legacy_roots.iter().filter(|root| evicting.has_root(&root[..])).count(), 1);
+
+        // the migrated tree keeps inserting like the original tree
+        tree.insert_commitments(&[leaf(6)]).unwrap();
+        migrated.insert_commitments(&[leaf(6)]).unwrap();
+        assert_eq!(migrated.root(), tree.root());
+        assert_eq!(migrated.header().filled_sub_trees, tree.header().filled_sub_trees);
+    }
+
+    #[test]
+    fn test_legacy_manager_tree_depth() {
+        // the deployed commitments manager is the length prefixed tree number
+        let mut manager_data = 8u64.to_le_bytes().to_vec();
+        manager_data.extend_from_slice(&2u64.to_le_bytes());
+        let manager = CommitmentsManagerAccount::load(&manager_data).unwrap();
+        assert_eq!(manager.incremental_tree_number, 2);
+        assert_eq!(manager.tree_depth as usize, LEGACY_TREE_DEPTH);
+        assert_eq!(manager.hash_function, HashFunction::Sha256 as u8);
+
+        // next to it the deployed tree of depth 15
+        let mut tree = CommitmentsAccount::new(2, LEGACY_TREE_DEPTH, 4);
+        tree.insert_commitments(&[leaf(0), leaf(1)]).unwrap();
+        let header = *tree.header();
+        let legacy = LegacyCommitmentsAccount {
+            next_leaf_index: 2,
+            merkle_root: tree.root(),
+            new_tree_root: ZEROS[LEGACY_TREE_DEPTH].to_vec(),
+            tree_number: 2,
+            zeros: ZEROS[..LEGACY_TREE_DEPTH].iter().map(|zero| zero.to_vec()).collect(),
+            filled_sub_trees: header.filled_sub_trees[..LEGACY_TREE_DEPTH]
+                .iter()
+                .map(|node| node.to_vec())
+                .collect(),
+            root_history: tree.root_history().iter().map(|root| (root.to_vec(), true)).collect(),
+            nullifiers: HashMap::new(),
+        };
+        let legacy_data = legacy.try_to_vec_with_length().unwrap();
+        let legacy = LegacyCommitmentsAccount::try_from_slice_with_length(&legacy_data).unwrap();
+
+        let root_history_size = manager.root_history_size as usize;
+        let migrated = CommitmentsAccount::migrate(
+            vec![0u8; commitments_account_space(root_history_size)],
+            &legacy,
+            root_history_size,
+        )
+        .unwrap();
+        assert_eq!(migrated.tree_depth(), manager.tree_depth as usize);
+        assert_eq!(migrated.root(), tree.root());
+
+        // the next tree keeps the depth of the deployed trees
+        let next_tree = CommitmentsAccount::new(3, manager.tree_depth as usize, root_history_size);
+        assert_eq!(next_tree.tree_depth(), LEGACY_TREE_DEPTH);
+        assert_eq!(next_tree.root(), ZEROS[LEGACY_TREE_DEPTH].to_vec());
+    }
 }
@@ -0,0 +1,98 @@
+use crate::accounts::check_address;
+use crate::error::DarksolError;
+use crate::utils::account::{create_pda_account_from_pda_account, create_pda_account_from_payer};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+pub const NULLIFIER_LENGTH: usize = 32;
+
+// derive_nullifier_pda derives the account marking a nullifier as spent.
+// Nullifier accounts are independent of the commitments trees so
+// a note can only be spent once across all trees
+pub fn derive_nullifier_pda(nullifier: &[u8], program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"nullifier", nullifier], program_id)
+}
+
+// is_nullifier_spent checks if the nullifier account is already
+// created and owned by the program
+pub fn is_nullifier_spent(program_id: &Pubkey, nullifier_account: &AccountInfo) -> bool {
+    nullifier_account.owner == program_id
+}
+
+// check_unspent_nullifier checks the account is the nullifier account of
+// an unspent nullifier and returns its bump seed
+fn check_unspent_nullifier(
+    program_id: &Pubkey,
+    nullifier_account: &AccountInfo,
+    nullifier: &[u8],
+) -> Result<u8, ProgramError> {
+    if nullifier.len() != NULLIFIER_LENGTH {
+        return Err(DarksolError::InvalidRequest.into());
+    }
+
+    let (nullifier_pda, bump_seed) = derive_nullifier_pda(nullifier, program_id);
+    check_address(nullifier_account, &nullifier_pda)?;
+
+    if is_nullifier_spent(program_id, nullifier_account) {
+        return Err(DarksolError::UtxoAlreadySpent.into());
+    }
+
+    Ok(bump_seed)
+}
+
+// insert_nullifier marks a nullifier as spent by creating its nullifier
+// account, the payer signing the spend ( the user wallet, or the relayer
+// in relayer mode ) pays for the rent. Fails if the nullifier is already
+// spent
+pub fn insert_nullifier(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    nullifier: &[u8],
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let payer_account = next_account_info(accounts_iter)?;
+    let nullifier_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let bump_seed = check_unspent_nullifier(program_id, nullifier_account, nullifier)?;
+
+    create_pda_account_from_payer(
+        payer_account,
+        0,
+        program_id,
+        system_program,
+        nullifier_account,
+        &[b"nullifier", nullifier, &[bump_seed]],
+    )
+}
+
+// insert_legacy_nullifier moves a nullifier spent in a legacy commitments
+// account to the registry, the funding account pays for the rent as the
+// spender of a legacy note never paid for its nullifier account
+pub fn insert_legacy_nullifier(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    nullifier: &[u8],
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let funding_account = next_account_info(accounts_iter)?;
+    let nullifier_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let bump_seed = check_unspent_nullifier(program_id, nullifier_account, nullifier)?;
+
+    create_pda_account_from_pda_account(
+        funding_account,
+        0,
+        program_id,
+        system_program,
+        nullifier_account,
+        &[b"nullifier", nullifier, &[bump_seed]],
+    )
+}
@@ -1,148 +1,169 @@
-use core::panic;
-use std::ops::{AddAssign, SubAssign};
+use std::ops::{AddAssign, DerefMut, SubAssign};
 
-use crate::merkle::CommitmentsAccount;
-use crate::state::initialize_commitments_manager;
-use crate::utils::account::{
-    create_pda_account_from_pda_account, get_associated_token_address_and_bump_seed,
+use crate::accounts::{
+    check_address, check_owner, DepositAccounts, InitializeAccounts, RelayerAccounts,
+    TransferAccounts, WithdrawAccounts,
+};
+use crate::config::{
+    check_not_paused, check_upgrade_authority, initialize_config, load_config, operation_fee,
+    validate_root_history_size, FeeOperation, PAUSE_DEPOSIT, PAUSE_TRANSFER, PAUSE_WITHDRAW,
+};
+use crate::merkle::{leaves_from_bytes, CommitmentsAccount};
+use crate::nullifier::insert_nullifier;
+use crate::verifier::verify_proof;
+use crate::state::{
+    extend_commitments_account, initialize_commitments_manager, migrate_commitments_account,
+    migrate_legacy_nullifiers, verify_commitment_inclusion,
 };
-use crate::utils::serialize::{BorshDeserializeWithLength, BorshSerializeWithLength};
 use crate::{
-    derive_pda, DepositEvent, DepositRequest, NullifierEvent, SP1Groth16Proof, TransactionEvent,
-    TransferRequest, WithdrawRequest,
+    derive_native_vault_pda, derive_pda, derive_vault_pda, BoundParams, DepositEvent, DepositRequest, NullifierEvent, Relayer, SpentTree, TransactionEvent,
+    OPERATION_TRANSFER, OPERATION_WITHDRAW,
+    TransferPublicValue, TransferRequest, WithdrawEvent, WithdrawPublicValue, WithdrawRequest,
 };
 use crate::{
     error::DarksolError,
-    merkle::hash_precommits,
     state::{initialize_commitments_account, CommitmentsManagerAccount},
-    TREE_DEPTH,
 };
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::instruction::Instruction;
 use solana_program::log::sol_log_data;
 use solana_program::msg;
 use solana_program::program::invoke;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
+    instruction::Instruction,
     program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
 };
-use veil_types::PublicValue;
 
-use solana_program::system_instruction;
 use solana_program::sysvar::{rent::Rent, Sysvar};
+use solana_system_interface::instruction as system_instruction;
+
+use spl_token_2022::extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions};
+use spl_token_2022::instruction::initialize_account;
+use spl_token_2022::state::{Account, Mint};
+
+// spl_transfer builds a token transfer for the SPL token or Token-2022
+// program. The plain transfer does not need the mint account, Token-2022
+// refuses it for mints with a transfer fee or a transfer hook
+#[allow(deprecated)]
+fn spl_transfer(
+    token_program_id: &Pubkey,
+    source_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    signer_pubkeys: &[&Pubkey],
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    spl_token_2022::instruction::transfer(
+        token_program_id,
+        source_pubkey,
+        destination_pubkey,
+        authority_pubkey,
+        signer_pubkeys,
+        amount,
+    )
+}
+
+// vault_account_len returns the size of a vault token account of the mint,
+// Token-2022 vaults hold the account extensions required by the mint
+fn vault_account_len(mint_account: &AccountInfo, token_program: &AccountInfo) -> Result<usize, ProgramError> {
+    if token_program.key != &spl_token_2022::ID {
+        return ExtensionType::try_calculate_account_len::<Account>(&[]);
+    }
 
-use spl_associated_token_account::get_associated_token_address;
-use spl_associated_token_account::instruction::create_associated_token_account;
-use spl_token::instruction::{initialize_account, transfer as spl_transfer};
-use spl_token::{solana_program::program_pack::Pack, state::Account};
+    let mint_data = mint_account.data.borrow();
+    let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+    let account_extensions =
+        ExtensionType::get_required_init_account_extensions(&mint.get_extension_types()?);
+    ExtensionType::try_calculate_account_len::<Account>(&account_extensions)
+}
 
-// transfer_token_in deposit user fund into contract owned account.
-// Create a new token account for the deposit account if it's not initialized yet
-// TODO: handle both native and spl token transfer
-fn transfer_token_in(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+// transfer_token_in deposit user fund into contract owned vault account.
+// Each mint has its own vault derived from (mint, token program), create
+// the vault token account on the first deposit of that mint. The deposit
+// fee is paid to the treasury on top of the amount
+fn transfer_token_in(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    fee: u64,
+) -> ProgramResult {
     let accounts_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
 
     let funding_account = next_account_info(accounts_iter)?;
     let user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
     let user_token_account = next_account_info(accounts_iter)?; // User's SPL token account
-    let associated_token_account = next_account_info(accounts_iter)?; // PDA token account
+    let vault_account = next_account_info(accounts_iter)?; // PDA token account for this mint
     let mint_account = next_account_info(accounts_iter)?; // SPL Token Mint
     let token_program = next_account_info(accounts_iter)?; // SPL Token Program
     let system_program = next_account_info(accounts_iter)?; // System Program for creating accounts
     let rent_sysvar = next_account_info(accounts_iter)?; // Rent Sysvar
-    let ata_program = next_account_info(accounts_iter)?; // Associated Token Program
+    let treasury_account = next_account_info(accounts_iter)?; // token account receiving the fee
 
     // Derive PDA funding account to pay for the new account
     // TODO: change the seeds
     let (funding_pda, bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], program_id);
-    if funding_account.key != &funding_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(funding_account, &funding_pda)?;
 
-    let (funding_ata, ata_bump) = get_associated_token_address_and_bump_seed(
-        &*funding_account.key,
-        &mint_account.key,
-        ata_program.key,
-        token_program.key,
-    );
+    let (vault_pda, vault_bump) =
+        derive_vault_pda(mint_account.key, token_program.key, program_id);
+    check_address(vault_account, &vault_pda)?;
 
-    let (funding_ata, ata_bump) = Pubkey::find_program_address(&[b"funding_ata"], program_id);
-
-    msg!("funding_ata: {:?}", ata_bump);
-
-    let ata_seed: &[&[u8]] = &[
-        b"funding_ata",
-        // &funding_account.key.to_bytes(),
-        // &token_program.key.to_bytes(),
-        // &mint_account.key.to_bytes(),
-        &[ata_bump],
+    let vault_seed: &[&[u8]] = &[
+        b"vault",
+        mint_account.key.as_ref(),
+        token_program.key.as_ref(),
+        &[vault_bump],
     ];
 
-    if associated_token_account.key != &funding_ata {
-        return Err(ProgramError::InvalidSeeds);
-    }
-
-    // TODO: apply deposit fee
-
-    // Check if PDA's token account is already initialized
-    if associated_token_account.data_is_empty() {
-        // PDA's token account is not initialized → Create it
-        // create_pda_account_from_pda_account(
-        //     funding_account,
-        //     spl_token::state::Account::LEN,
-        //     token_program.key,
-        //     system_program,
-        //     associated_token_account,
-        //     ata_seed,
-        // )?;
-
+    // Check if the vault token account of this mint is already initialized
+    if vault_account.data_is_empty() {
+        // vault token account is not initialized → Create it
         let rent: &Rent = &Rent::get()?;
 
-        let required_lamports = rent.minimum_balance(spl_token::state::Account::LEN);
+        let vault_len = vault_account_len(mint_account, token_program)?;
+        let required_lamports = rent
+            .minimum_balance(vault_len)
+            .saturating_sub(vault_account.lamports());
 
-        msg!("Allocating PDA");
+        msg!("Allocating vault");
         invoke_signed(
-            &system_instruction::allocate(
-                associated_token_account.key,
-                spl_token::state::Account::LEN.try_into().unwrap(),
-            ),
-            &[associated_token_account.clone(), system_program.clone()],
-            &[ata_seed],
+            &system_instruction::allocate(vault_account.key, vault_len as u64),
+            &[vault_account.clone(), system_program.clone()],
+            &[vault_seed],
         )?;
 
-        msg!("Assigning PDA");
+        msg!("Assigning vault");
         invoke_signed(
-            &system_instruction::assign(associated_token_account.key, token_program.key),
-            &[associated_token_account.clone(), system_program.clone()],
-            &[ata_seed],
+            &system_instruction::assign(vault_account.key, token_program.key),
+            &[vault_account.clone(), system_program.clone()],
+            &[vault_seed],
         )?;
 
-        msg!("Funding PDA");
+        msg!("Funding vault");
         if required_lamports > 0 {
             funding_account
                 .lamports
                 .borrow_mut()
                 .sub_assign(required_lamports);
-            associated_token_account
+            vault_account
                 .lamports
                 .borrow_mut()
                 .add_assign(required_lamports);
         }
 
-        msg!("Initializing token account");
+        msg!("Initializing vault token account");
         invoke_signed(
             // TODO: emit error
             &initialize_account(
                 token_program.key,
-                associated_token_account.key,
+                vault_account.key,
                 mint_account.key,
-                &funding_account.key, // PDA is the owner of this token account
+                funding_account.key, // PDA is the owner of this token account
             )?,
             &[
-                associated_token_account.clone(),
+                vault_account.clone(),
                 mint_account.clone(),
                 funding_account.clone(),
                 rent_sysvar.clone(),
@@ -151,7 +172,7 @@ fn transfer_token_in(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64)
             &[&[b"funding_pda", &[bump_seed]]], // PDA signs
         )?;
     }
-    msg!("created funding ata");
+    msg!("created vault for mint: {}", mint_account.key);
 
     // transfer token to contract owned token account
     invoke(
@@ -159,55 +180,73 @@ fn transfer_token_in(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64)
         &spl_transfer(
             token_program.key,
             user_token_account.key,
-            associated_token_account.key,
+            vault_account.key,
             user_wallet.key, // User must sign as authority
             &[],
             amount,
         )?,
         &[
             user_token_account.clone(),
-            associated_token_account.clone(),
+            vault_account.clone(),
             user_wallet.clone(),
             token_program.clone(),
         ],
     )?;
-    msg!("transfered to funding ata");
+    msg!("transfered to vault");
+
+    if fee > 0 {
+        invoke(
+            &spl_transfer(
+                token_program.key,
+                user_token_account.key,
+                treasury_account.key,
+                user_wallet.key, // User must sign as authority
+                &[],
+                fee,
+            )?,
+            &[
+                user_token_account.clone(),
+                treasury_account.clone(),
+                user_wallet.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        msg!("transfered deposit fee to treasury: {}", fee);
+    }
 
     Ok(())
 }
 
-fn transfer_token_out(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+// transfer_token_out withdraw fund from the vault of the given token id
+// to the receiver token account, the withdraw fee is taken from the
+// amount and paid to the treasury
+fn transfer_token_out(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    token_id: &[u8],
+    amount: u64,
+    fee: u64,
+) -> ProgramResult {
     let accounts_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
 
     let funding_account = next_account_info(accounts_iter)?;
-    let user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
+    let _user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
     let user_token_account = next_account_info(accounts_iter)?; // User's SPL token account
-    let pda_token_account = next_account_info(accounts_iter)?; // PDA token account
+    let vault_account = next_account_info(accounts_iter)?; // PDA token account for this mint
     let token_program = next_account_info(accounts_iter)?; // SPL Token Program
-    
-    // TODO: apply withdraw fee
-    let (funding_ata, ata_bump) = Pubkey::find_program_address(&[b"funding_ata"], program_id);
-
+    let treasury_account = next_account_info(accounts_iter)?; // token account receiving the fee
 
-    msg!("funding_ata: {:?}", ata_bump);
+    // route the withdraw to the vault matching the shielded token
+    let mint = Pubkey::try_from(token_id).map_err(|_| DarksolError::InvalidTokenId)?;
+    let (vault_pda, _vault_bump) = derive_vault_pda(&mint, token_program.key, program_id);
+    check_address(vault_account, &vault_pda)?;
 
-    let ata_info = spl_token::state::Account::unpack(&pda_token_account.data.borrow())?;
-    if ata_info.amount < amount {
+    let vault_amount = StateWithExtensions::<Account>::unpack(&vault_account.data.borrow())?.base.amount;
+    if vault_amount < amount || amount < fee {
         return Err(ProgramError::InsufficientFunds);
     }
 
-    msg!("ata_amout: {}", ata_info.amount);
-    let ata_seed: &[&[u8]] = &[
-        b"funding_ata",
-        // &funding_account.key.to_bytes(),
-        // &token_program.key.to_bytes(),
-        // &mint_account.key.to_bytes(),
-        &[ata_bump],
-    ];
-
-    if pda_token_account.key != &funding_ata {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    msg!("vault amount: {}", vault_amount);
 
     // Derive PDA funding account to pay for the new account
     // TODO: change the seeds
@@ -220,23 +259,20 @@ fn transfer_token_out(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64
     ];
 
     // check all the accounts info
-    if funding_account.key != &funding_pda
-    {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(funding_account, &funding_pda)?;
 
     // transfer token from contract owned token account to user token address
     invoke_signed(
         &spl_transfer(
             token_program.key,
-            pda_token_account.key,  // Source - program's ATA
-            user_token_account.key,        // Destination - user's token account
-            funding_account.key,           // Authority - PDA that owns the ATA
+            vault_account.key,      // Source - program's vault of this mint
+            user_token_account.key, // Destination - user's token account
+            funding_account.key,    // Authority - PDA that owns the vault
             &[],
-            amount,
+            amount - fee,
         )?,
         &[
-            pda_token_account.clone(),
+            vault_account.clone(),
             user_token_account.clone(),
             funding_account.clone(),
             token_program.clone(),
@@ -244,99 +280,332 @@ fn transfer_token_out(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64
         &[funding_seed], // PDA signs the transaction
     )?;
 
+    if fee > 0 {
+        invoke_signed(
+            &spl_transfer(
+                token_program.key,
+                vault_account.key,
+                treasury_account.key,
+                funding_account.key,
+                &[],
+                fee,
+            )?,
+            &[
+                vault_account.clone(),
+                treasury_account.clone(),
+                funding_account.clone(),
+                token_program.clone(),
+            ],
+            &[funding_seed],
+        )?;
+        msg!("transfered withdraw fee to treasury: {}", fee);
+    }
+
     Ok(())
 }
 
-// process_deposit_fund deposit user fund into contract owned account
-// insert new UTXO into current merkel tree, if exceeds maximum tree depth
-// create new account to store new tree
-pub fn process_deposit_fund(
+// transfer_native_in deposit user lamports into the program owned lamport vault.
+// Assign the vault to the program on the first native deposit. The deposit
+// fee is paid to the treasury on top of the amount
+fn transfer_native_in(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    request: DepositRequest,
+    amount: u64,
+    fee: u64,
 ) -> ProgramResult {
     let accounts_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
 
-    let funding_account = next_account_info(accounts_iter)?;
     let user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
-    let user_ata_account = next_account_info(accounts_iter)?; // User's SPL token account
-    let funding_ata_account = next_account_info(accounts_iter)?; // PDA token account
-    let mint_account: &AccountInfo<'_> = next_account_info(accounts_iter)?; // SPL Token Mint
-    let commitments_account = next_account_info(accounts_iter)?; // current commitments account
-    let commitments_manager_account = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?; // SPL Token Program
-    let system_program = next_account_info(accounts_iter)?; // System Program for creating accounts
-    let rent_sysvar = next_account_info(accounts_iter)?; // Rent Sysvar
-    let ata_program = next_account_info(accounts_iter)?; // Associated Token Program
+    let native_vault = next_account_info(accounts_iter)?; // PDA lamport vault
+    let system_program = next_account_info(accounts_iter)?; // System Program for assigning the vault
+    let treasury_account = next_account_info(accounts_iter)?; // wallet receiving the fee
+
+    let (native_vault_pda, native_vault_bump) = derive_native_vault_pda(program_id);
+    check_address(native_vault, &native_vault_pda)?;
+
+    // the user also pays the rent of the lamport vault on the first deposit
+    let is_initialized = native_vault.owner == program_id;
+    let mut lamports = amount;
+    if !is_initialized {
+        let rent: &Rent = &Rent::get()?;
+        lamports = rent
+            .minimum_balance(0)
+            .saturating_sub(native_vault.lamports())
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+    }
+
+    // transfer lamports to contract owned lamport vault
+    invoke(
+        &system_instruction::transfer(user_wallet.key, native_vault.key, lamports),
+        &[
+            user_wallet.clone(),
+            native_vault.clone(),
+            system_program.clone(),
+        ],
+    )?;
 
-    if commitments_account.owner != program_id || commitments_manager_account.owner != program_id {
-        return Err(ProgramError::IncorrectProgramId);
+    if !is_initialized {
+        msg!("Assigning native vault");
+        invoke_signed(
+            &system_instruction::assign(native_vault.key, program_id),
+            &[native_vault.clone(), system_program.clone()],
+            &[&[b"native_vault", &[native_vault_bump]]],
+        )?;
     }
+    msg!("transfered to native vault");
 
-    // Ensure the user_wallet signed the transaction
-    if !user_wallet.is_signer {
-        return Err(ProgramError::MissingRequiredSignature);
+    if fee > 0 {
+        invoke(
+            &system_instruction::transfer(user_wallet.key, treasury_account.key, fee),
+            &[
+                user_wallet.clone(),
+                treasury_account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+        msg!("transfered deposit fee to treasury: {}", fee);
     }
 
-    // fetch the current tree number
-    let data = commitments_manager_account.data.borrow_mut();
+    Ok(())
+}
+
+// transfer_native_out withdraw lamports from the lamport vault to the
+// receiver wallet, the withdraw fee is taken from the amount and paid
+// to the treasury
+fn transfer_native_out(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    fee: u64,
+) -> ProgramResult {
+    let accounts_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
 
-    // deserialize the data
-    let manager_data: CommitmentsManagerAccount =
-        CommitmentsManagerAccount::try_from_slice_with_length(&data)?;
+    let native_vault = next_account_info(accounts_iter)?; // PDA lamport vault
+    let user_wallet = next_account_info(accounts_iter)?; // receiver wallet
+    let treasury_account = next_account_info(accounts_iter)?; // wallet receiving the fee
+
+    let (native_vault_pda, _native_vault_bump) = derive_native_vault_pda(program_id);
+    check_address(native_vault, &native_vault_pda)?;
+
+    check_owner(native_vault, program_id)?;
+
+    // the vault must stay rent exempt after the withdraw
+    let rent: &Rent = &Rent::get()?;
+    let available = native_vault
+        .lamports()
+        .saturating_sub(rent.minimum_balance(0));
+    if available < amount || amount < fee {
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    **native_vault.lamports.borrow_mut() -= amount;
+    **user_wallet.lamports.borrow_mut() += amount - fee;
+    **treasury_account.lamports.borrow_mut() += fee;
+
+    Ok(())
+}
+
+// pay_relayer pays the relayer fee from the vault of the relayer token to
+// the relayer account committed in the proof. The relayer receives it as a
+// withdraw without fee
+fn pay_relayer<'info>(
+    program_id: &Pubkey,
+    funding_account: &AccountInfo<'info>,
+    relayer_accounts: &RelayerAccounts<'_, 'info>,
+    relayer: &Relayer,
+) -> ProgramResult {
+    let relayer_account = relayer_accounts.relayer;
+    let vault_account = relayer_accounts.vault;
+
+    if relayer_account.key.as_ref() != relayer.pubkey.as_slice() {
+        return Err(DarksolError::RelayerNotMatch.into());
+    }
+
+    if relayer.fee == 0 {
+        return Ok(());
+    }
+
+    if relayer.is_native() {
+        transfer_native_out(
+            program_id,
+            &[
+                vault_account.clone(),
+                relayer_account.clone(),
+                relayer_account.clone(),
+            ],
+            relayer.fee,
+            0,
+        )?;
+    } else {
+        let token_program = relayer_accounts
+            .token_program
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        transfer_token_out(
+            program_id,
+            &[
+                funding_account.clone(),
+                relayer_account.clone(),
+                relayer_account.clone(),
+                vault_account.clone(),
+                token_program.clone(),
+                relayer_account.clone(),
+            ],
+            &relayer.token_id,
+            relayer.fee,
+            0,
+        )?;
+    }
+    msg!("paid relayer fee: {}", relayer.fee);
+
+    Ok(())
+}
+
+// check_spent_trees checks each spent UTXO is proven against a root in the
+// history of its commitments tree, with one commitments account per input
+// in the nullifiers order. Inputs can come from different trees but all
+// trees must have the depth the proof is generated with. Returns the next
+// leaf index of the first spent tree
+fn check_spent_trees(
+    program_id: &Pubkey,
+    spent_commitments_accounts: &[&AccountInfo],
+    spent_trees: &[SpentTree],
+    tree_depth: u64,
+) -> Result<u64, ProgramError> {
+    let mut first_next_leaf_index: u64 = 0;
+    for (i, (spent_commitments_account, spent_tree)) in
+        spent_commitments_accounts.iter().zip(spent_trees).enumerate()
+    {
+        check_owner(spent_commitments_account, program_id)?;
+
+        // Derive the PDA for the commitments account
+        let (account_pda, _bump_seed) = derive_pda(spent_tree.tree_number, program_id);
+        check_address(spent_commitments_account, &account_pda)?;
+
+        // the spent tree can also be the current tree so release the borrow here
+        let spent_commitments_acc_data = spent_commitments_account.data.borrow();
+        let tree = CommitmentsAccount::load(&spent_commitments_acc_data[..])?;
+
+        // the proof must be generated for the depth of the spent tree
+        if tree.tree_depth() as u64 != tree_depth {
+            return Err(DarksolError::TreeDepthMismatch.into());
+        }
+
+        if !tree.has_root(&spent_tree.root) {
+            return Err(DarksolError::InvalidMerkelRoot.into());
+        }
+
+        if i == 0 {
+            first_next_leaf_index = tree.next_leaf_index();
+        }
+    }
+
+    Ok(first_next_leaf_index)
+}
+
+// process_deposit_fund deposit user fund into contract owned account
+// insert new UTXO into current merkel tree, if exceeds maximum tree depth
+// create new account to store new tree
+pub fn process_deposit_fund(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    request: DepositRequest,
+) -> ProgramResult {
+    let DepositAccounts {
+        funding: funding_account,
+        user_wallet,
+        user_token: user_ata_account,
+        vault: vault_account,
+        mint: mint_account,
+        commitments: commitments_account,
+        commitments_manager: commitments_manager_account,
+        config: config_account,
+        fee_config: fee_config_account,
+        treasury: treasury_account,
+        token_program,
+        system_program,
+        rent_sysvar,
+        new_commitments: new_commitments_account,
+    } = DepositAccounts::parse(program_id, accounts, &request)?;
+
+    check_not_paused(program_id, config_account, PAUSE_DEPOSIT)?;
+
+    // fetch the current tree number
+    // the borrow is released right away as a new commitments account
+    // updates the manager
+    let manager_data =
+        CommitmentsManagerAccount::load(&commitments_manager_account.data.borrow())?;
 
     // Derive the PDA for the current commitments account
     let (account_pda, _bump_seed) = derive_pda(manager_data.incremental_tree_number, program_id);
 
     // Ensure the provided new_account is the correct PDA
-    if commitments_account.key != &account_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(commitments_account, &account_pda)?;
 
-    // transfer token to contract owned account
-    transfer_token_in(
+    // the deposit fee is paid on top of the note value
+    let fee = operation_fee(
         program_id,
-        &[
-            funding_account.clone(),
-            user_wallet.clone(),
-            user_ata_account.clone(),
-            funding_ata_account.clone(),
-            mint_account.clone(),
-            token_program.clone(),
-            system_program.clone(),
-            rent_sysvar.clone(),
-            ata_program.clone(),
-        ],
+        fee_config_account,
+        treasury_account,
+        &request.pre_commitments.token_id,
         request.pre_commitments.value,
+        FeeOperation::Deposit,
     )?;
 
-    let inserted_leaf = hash_precommits(request.pre_commitments.clone());
-
-    // fetch current tree number
-    let mut commitments_data = &mut commitments_account.data.borrow_mut()[..];
+    // transfer token to contract owned account
+    if let (Some(user_ata_account), Some(mint_account), Some(token_program), Some(rent_sysvar)) =
+        (user_ata_account, mint_account, token_program, rent_sysvar)
+    {
+        transfer_token_in(
+            program_id,
+            &[
+                funding_account.clone(),
+                user_wallet.clone(),
+                user_ata_account.clone(),
+                vault_account.clone(),
+                mint_account.clone(),
+                token_program.clone(),
+                system_program.clone(),
+                rent_sysvar.clone(),
+                treasury_account.clone(),
+            ],
+            request.pre_commitments.value,
+            fee,
+        )?;
+    } else {
+        transfer_native_in(
+            program_id,
+            &[
+                user_wallet.clone(),
+                vault_account.clone(),
+                system_program.clone(),
+                treasury_account.clone(),
+            ],
+            request.pre_commitments.value,
+            fee,
+        )?;
+    }
 
-    // deserialize the data
-    let mut current_tree = CommitmentsAccount::try_from_slice_with_length(&commitments_data)?;
-    let mut current_tree_number: u64 = manager_data.incremental_tree_number;
-    let start_position: u64 = 0;
+    // load the current tree in place
+    let mut commitments_data = commitments_account.data.borrow_mut();
+    let mut current_tree = CommitmentsAccount::load(&mut commitments_data[..])?;
+    let current_tree_number: u64 = manager_data.incremental_tree_number;
 
     msg!("current tree number: {:?}", current_tree_number);
 
     // create new commitments account if insert leaf exceeds max tree depth
     // user should check if the inserted leafs exceeds max tree depth to
     // add new commitments account to the instruction
-    if current_tree.exceed_tree_depth(1) {
+    let event = if current_tree.exceed_tree_depth(1) {
         msg!("exceed_tree_depth");
-        let new_commitments_account = next_account_info(accounts_iter)?; // new commitments account
+        let new_commitments_account =
+            new_commitments_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
 
         // derive a new commitments account and update the commitments account
         let (new_pda, _bump_seed) = derive_pda(current_tree_number + 1, program_id);
+        check_address(new_commitments_account, &new_pda)?;
 
-        if new_commitments_account.key != &new_pda {
-            return Err(ProgramError::InvalidSeeds);
-        }
-
-        current_tree = initialize_commitments_account(
+        initialize_commitments_account(
             program_id,
             &[
                 funding_account.clone(),
@@ -346,33 +615,50 @@ pub fn process_deposit_fund(
             ],
         )?;
 
-        let mut new_commitments_data = &mut new_commitments_account.data.borrow_mut()[..];
-        current_tree_number += 1;
+        let mut new_commitments_data = new_commitments_account.data.borrow_mut();
+        let mut new_tree =
+            CommitmentsAccount::load(&mut new_commitments_data[..])?;
 
         // insert leaf into tree
-        current_tree.insert_commitments(&mut vec![inserted_leaf.clone()], &mut new_commitments_data)
-            .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
+        insert_deposit(&mut new_tree, &request, fee)?
     } else {
         // insert leaf into tree
         msg!("not exceed_tree_depth");
 
-        current_tree.insert_commitments(&mut vec![inserted_leaf.clone()], &mut commitments_data)
-            .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
-    }
+        insert_deposit(&mut current_tree, &request, fee)?
+    };
 
     // emit events for indexer to scan
-    let event = DepositEvent {
-        start_position,
-        tree_number: current_tree_number,
-        pre_commitments: request.pre_commitments.clone(),
-        shield_cipher_text: request.shield_cipher_text.clone(),
-    };
     let serialize_event = borsh::to_vec(&event)?;
     sol_log_data(&[b"deposit_event", &serialize_event]);
 
     Ok(())
 }
 
+// insert_deposit inserts the deposited note in the tree and returns the
+// deposit event with the leaf index of the note and the new tree root
+pub fn insert_deposit<D: DerefMut<Target = [u8]>>(
+    tree: &mut CommitmentsAccount<D>,
+    request: &DepositRequest,
+    fee: u64,
+) -> Result<DepositEvent, ProgramError> {
+    // the leaf is hashed with the hash function of the tree it is inserted in
+    let inserted_leaf = tree.hash_function()?.hash_precommits(&request.pre_commitments)?;
+
+    let insert_result = tree
+        .insert_commitments(&[inserted_leaf])
+        .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
+
+    Ok(DepositEvent {
+        start_position: insert_result.first_index,
+        tree_number: tree.tree_number(),
+        merkle_root: insert_result.new_root.to_vec(),
+        pre_commitments: request.pre_commitments.clone(),
+        shield_cipher_text: request.shield_cipher_text.clone(),
+        fee,
+    })
+}
+
 // process_transfer_asset takes the zkp for ownership of the UTXO
 // in the merkel tree, check the nullifier for that UTXO is marked on the list or not
 // and inserts new encrypted UTXO commitments to the merkle tree
@@ -381,118 +667,129 @@ pub fn process_transfer_asset(
     accounts: &[AccountInfo],
     request: TransferRequest,
 ) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-
-    let user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
-    let spent_commitments_account = next_account_info(accounts_iter)?; // commitments account contains spent UTXO
-    let current_commitments_account = next_account_info(accounts_iter)?; // current commitments account
-    let commitments_manager_account = next_account_info(accounts_iter)?;
-    let verification_account = next_account_info(accounts_iter)?; // verification account
-
-    // Ensure the user_wallet signed the transaction
-    if !user_wallet.is_signer {
-        return Err(ProgramError::MissingRequiredSignature);
-    }
-
-    if spent_commitments_account.owner != program_id
-        || commitments_manager_account.owner != program_id
-    {
-        return Err(ProgramError::IncorrectProgramId);
+    // one spent tree per nullifier, the commitments account of
+    // each spent tree follows the fixed accounts
+    if request.spent_trees.len() != request.nullifiers.len() {
+        return Err(DarksolError::InvalidRequest.into());
     }
 
-    // Derive the PDA for the commitments account
-    let (account_pda, _bump_seed) = derive_pda(request.metadata.tree_number, program_id);
-    // Ensure the provided new_account is the correct PDA
-    if spent_commitments_account.key != &account_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    let TransferAccounts {
+        user_wallet: _,
+        current_commitments: current_commitments_account,
+        commitments_manager: commitments_manager_account,
+        config: config_account,
+        verification_program,
+        vk_registry: vk_registry_account,
+        funding: funding_account,
+        system_program,
+        payer,
+        relayer: relayer_accounts,
+        spent_commitments: spent_commitments_accounts,
+        nullifiers: nullifier_accounts,
+        new_commitments: new_commitments_account,
+    } = TransferAccounts::parse(program_id, accounts, &request)?;
+
+    // proofs are only verified with the verification program pinned in the config
+    let config = load_config(program_id, config_account)?;
+    config.check_not_paused(PAUSE_TRANSFER)?;
+    config.check_verification_program(verification_program)?;
 
     // fetch the current tree number
-    let data = commitments_manager_account.data.borrow_mut();
-    // deserialize the data
-    let manager_data: CommitmentsManagerAccount =
-        CommitmentsManagerAccount::try_from_slice_with_length(&data)?;
+    // the borrow is released right away as a new commitments account
+    // updates the manager
+    let manager_data =
+        CommitmentsManagerAccount::load(&commitments_manager_account.data.borrow())?;
     let mut current_tree_number = manager_data.incremental_tree_number;
 
     // Derive the PDA for the commitments account
     let (pda, _bump_seed) = derive_pda(current_tree_number, program_id);
     // Ensure the provided new_account is the correct PDA
-    if current_commitments_account.key != &pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(current_commitments_account, &pda)?;
 
-    let mut spent_commitments_acc_data = &mut spent_commitments_account.data.borrow_mut()[..];
-    let mut spent_tree: CommitmentsAccount<TREE_DEPTH> =
-        CommitmentsAccount::try_from_slice_with_length(&spent_commitments_acc_data)?;
+    // check the roots are in the roots history of the spent trees, a root
+    // only has to be in the history so deposits landing in between do not
+    // invalidate the proof
+    check_spent_trees(
+        program_id,
+        &spent_commitments_accounts,
+        &request.spent_trees,
+        request.metadata.tree_depth,
+    )?;
 
-    let mut current_commitments_acc_data =
-        if current_commitments_account.key == spent_commitments_account.key {
-            &mut spent_commitments_acc_data
-        } else {
-            &mut current_commitments_account.data.borrow_mut()[..]
-        };
-    let mut inserted_tree: CommitmentsAccount<TREE_DEPTH> =
-        CommitmentsAccount::try_from_slice_with_length(&current_commitments_acc_data)?;
+    // bind the instruction context, the receivers are bound
+    // by the output hashes and transfers take no protocol fee
+    let params_hash = BoundParams {
+        program_id: program_id.to_bytes().to_vec(),
+        tree_numbers: request.spent_trees.iter().map(|spent_tree| spent_tree.tree_number).collect(),
+        operation: OPERATION_TRANSFER,
+        recipient: Vec::new(),
+        fee: 0,
+        relayer: request.relayer.clone(),
+    }
+    .hash()?;
 
-    // Deserialize the SP1Groth16Proof from the instruction data.
-    let public_value = PublicValue {
-        root: spent_tree.root(),
+    // verify against the roots the proof was generated with
+    let public_value = TransferPublicValue {
+        spent_trees: request.spent_trees.clone(),
+        params_hash,
         nullifiers: request.nullifiers.clone(),
         output_hashes: request.encrypted_commitments.clone(),
+        tree_depth: request.metadata.tree_depth,
+        relayer: request.relayer.clone(),
     };
 
     let public_values_bytes = borsh::to_vec(&public_value)?;
-    let groth16_proof = SP1Groth16Proof{
-        proof: request.proof,
-        sp1_public_inputs: public_values_bytes,
-    };
-
-    // Create an instruction to invoke the verification program.
-    let instruction = Instruction::new_with_borsh(
-        *verification_account.key,
-        &groth16_proof,
-        vec![],
-    );
-    invoke(&instruction, accounts)?;
-
-    // check if merkle root is valid
-    msg!("request merkle root: {:?}", request.merkle_root);
-
-    if !spent_tree.has_root(&request.merkle_root) {
-        return Err(DarksolError::InvalidMerkelRoot.into());
-    }
+    verify_proof(
+        verification_program,
+        vk_registry_account,
+        OPERATION_TRANSFER,
+        request.metadata.circuit_id,
+        request.proof,
+        public_values_bytes,
+    )?;
 
     // ------------------- verify logic end here ------------------------ //
 
-    // check if nullifiers already exists if not added to the list
-    for idx in 0..request.nullifiers.len() {
-        if spent_tree.check_nullifier(&request.nullifiers[idx]) {
-            return Err(DarksolError::UtxoAlreadySpent.into());
-        }
+    // mark nullifiers as spent in the nullifier registry, one nullifier
+    // account per nullifier follows the fixed accounts. Fails if any of
+    // them is already spent in any tree
+    for (nullifier, nullifier_account) in request.nullifiers.iter().zip(nullifier_accounts) {
+        insert_nullifier(
+            program_id,
+            &[
+                payer.clone(),
+                nullifier_account.clone(),
+                system_program.clone(),
+            ],
+            nullifier,
+        )?;
+    }
 
-        spent_tree.insert_nullifier(request.nullifiers[idx].clone());
+    // the relayer fee is paid out of the transferred value
+    if let (Some(relayer), Some(relayer_accounts)) = (&request.relayer, &relayer_accounts) {
+        pay_relayer(program_id, funding_account, relayer_accounts, relayer)?;
     }
 
-    let start_position: u64;
+    let commitments = leaves_from_bytes(&request.encrypted_commitments)?;
+
+    let mut current_commitments_acc_data = current_commitments_account.data.borrow_mut();
+    let mut inserted_tree =
+        CommitmentsAccount::load(&mut current_commitments_acc_data[..])?;
 
     // update merkle tree
     // create new commitments account if insert leaf exceeds max tree depth
     // user should check if the inserted leafs exceeds max tree depth to
-    // add new commitments account, funding account and system program to the instruction
-    if inserted_tree.exceed_tree_depth(request.encrypted_commitments.len()) {
-        let funding_account = next_account_info(accounts_iter)?;
-        let new_commitments_account = next_account_info(accounts_iter)?; // new commitments account
-        let system_program = next_account_info(accounts_iter)?; // System Program for creating accounts
+    // add new commitments account to the instruction
+    let insert_result = if inserted_tree.exceed_tree_depth(commitments.len()) {
+        let new_commitments_account =
+            new_commitments_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
 
         current_tree_number += 1;
         // derive a new commitments account and update the commitments account
         let (new_pda, _bump_seed) = derive_pda(current_tree_number, program_id);
+        check_address(new_commitments_account, &new_pda)?;
 
-        if new_commitments_account.key != &new_pda {
-            return Err(ProgramError::InvalidSeeds);
-        }
-
-        inserted_tree = initialize_commitments_account(
+        initialize_commitments_account(
             program_id,
             &[
                 funding_account.clone(),
@@ -502,33 +799,23 @@ pub fn process_transfer_asset(
             ],
         )?;
 
-        let mut new_commitments_data = &mut new_commitments_account.data.borrow_mut()[..];
+        let mut new_commitments_data = new_commitments_account.data.borrow_mut();
+        let mut new_tree =
+            CommitmentsAccount::load(&mut new_commitments_data[..])?;
 
         // insert leaf into tree
-        let result = inserted_tree.insert_commitments(&mut request.encrypted_commitments.clone(), &mut new_commitments_data);
-        match result {
-            Ok(next_leaf_index) => {
-                start_position = next_leaf_index;
-            }
-            Err(_err) => return Err(DarksolError::FailedInsertCommitmentHash.into()),
-        }
+        new_tree.hash_function()?.check_leaves(&commitments)?;
+        new_tree.insert_commitments(&commitments)
     } else {
         // insert leaf into tree
-        let result = inserted_tree.insert_commitments(&mut request.encrypted_commitments.clone(), &mut current_commitments_acc_data);
-        match result {
-            Ok(next_leaf_index) => {
-                start_position = next_leaf_index;
-            }
-            Err(_err) => return Err(DarksolError::FailedInsertCommitmentHash.into()),
-        }
+        inserted_tree.hash_function()?.check_leaves(&commitments)?;
+        inserted_tree.insert_commitments(&commitments)
     }
-
-    // update nullifiers list
-    spent_tree.serialize_with_length(&mut spent_commitments_acc_data)?;
+    .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
 
     // emit event
     let event = TransactionEvent {
-        start_position,
+        start_position: insert_result.first_index,
         tree_number: current_tree_number,
         commitments: request.encrypted_commitments.clone(),
         commitment_cipher_text: request.commitment_cipher_text.clone(),
@@ -553,122 +840,177 @@ pub fn process_withdraw_asset(
     accounts: &[AccountInfo],
     request: WithdrawRequest,
 ) -> ProgramResult {
-    let accounts_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
-
-    let funding_account = next_account_info(accounts_iter)?;
-    let spent_commitments_account = next_account_info(accounts_iter)?; // commitments account for the request tree number
-    let commitments_manager_account = next_account_info(accounts_iter)?;
-    let user_wallet = next_account_info(accounts_iter)?; // User's SOL wallet (payer)
-    let user_token_account = next_account_info(accounts_iter)?; // User's SPL token account
-    let pda_token_account = next_account_info(accounts_iter)?; // PDA token account
-    let token_program = next_account_info(accounts_iter)?; // SPL Token Program
-    let verification_program = next_account_info(accounts_iter)?; // verification program
-    
-    if spent_commitments_account.owner != program_id {
-        return Err(ProgramError::IncorrectProgramId);
+    // one spent tree per nullifier, the commitments account of
+    // each spent tree follows the fixed accounts
+    if request.spent_trees.len() != request.nullifiers.len() {
+        return Err(DarksolError::InvalidRequest.into());
     }
 
-    // Derive the PDA for the commitments account
-    let (account_pda, _bump_seed) = derive_pda(request.metadata.tree_number, program_id);
-    // Ensure the provided new_account is the correct PDA
-    if spent_commitments_account.key != &account_pda {
-        return Err(ProgramError::InvalidSeeds);
+    // the relayer fee is paid with the withdrawn token from the same vault
+    if let Some(relayer) = &request.relayer {
+        if relayer.token_id != request.pre_commitments.token_id {
+            return Err(DarksolError::InvalidTokenId.into());
+        }
     }
 
-    // Ensure the user_wallet signed the transaction
-    if !user_wallet.is_signer {
-        return Err(ProgramError::MissingRequiredSignature);
-    }
+    let WithdrawAccounts {
+        funding: funding_account,
+        commitments_manager: commitments_manager_account,
+        config: config_account,
+        fee_config: fee_config_account,
+        treasury: treasury_account,
+        user_wallet,
+        user_token: user_token_account,
+        vault: vault_account,
+        token_program,
+        verification_program,
+        vk_registry: vk_registry_account,
+        system_program,
+        payer,
+        relayer: relayer_account,
+        spent_commitments: spent_commitments_accounts,
+        nullifiers: nullifier_accounts,
+        current_commitments: current_commitment_account,
+        new_commitments: new_commitments_account,
+    } = WithdrawAccounts::parse(program_id, accounts, &request)?;
+
+    // proofs are only verified with the verification program pinned in the config
+    let config = load_config(program_id, config_account)?;
+    config.check_not_paused(PAUSE_WITHDRAW)?;
+    config.check_verification_program(verification_program)?;
+
+    // check the roots are in the roots history of the spent trees
+    let spent_next_leaf_index = check_spent_trees(
+        program_id,
+        &spent_commitments_accounts,
+        &request.spent_trees,
+        request.metadata.tree_depth,
+    )?;
 
-    let mut spent_commitments_acc_data = &mut spent_commitments_account.data.borrow_mut()[..];
-    let mut spent_tree: CommitmentsAccount<TREE_DEPTH> =
-        CommitmentsAccount::try_from_slice_with_length(&spent_commitments_acc_data)?;
+    // the recipient token account must hold the withdrawn token, native SOL
+    // is paid to the user wallet
+    let recipient = match user_token_account {
+        Some(user_token_account) => {
+            let token_account =
+                StateWithExtensions::<Account>::unpack(&user_token_account.data.borrow())?.base;
+            if token_account.mint.as_ref() != request.pre_commitments.token_id.as_slice() {
+                return Err(DarksolError::RecipientMintNotMatch.into());
+            }
+            user_token_account.key
+        }
+        None => user_wallet.key,
+    };
 
-    // check if merkle root is valid
-    if !spent_tree.has_root(&request.merkle_root) {
-        return Err(DarksolError::InvalidMerkelRoot.into());
+    // the withdraw fee is taken from the withdrawn amount
+    let fee = operation_fee(
+        program_id,
+        fee_config_account,
+        treasury_account,
+        &request.pre_commitments.token_id,
+        request.pre_commitments.value,
+        FeeOperation::Withdraw,
+    )?;
+
+    // the relayer fee is also taken from the withdrawn amount
+    let relayer_fee = request.relayer.as_ref().map_or(0, |relayer| relayer.fee);
+    let recipient_amount = request
+        .pre_commitments
+        .value
+        .checked_sub(relayer_fee)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    // bind the amount, fee, token and recipient actually used into the proof
+    // public values so the withdraw can not be redirected or inflated
+    let params_hash = BoundParams {
+        program_id: program_id.to_bytes().to_vec(),
+        tree_numbers: request.spent_trees.iter().map(|spent_tree| spent_tree.tree_number).collect(),
+        operation: OPERATION_WITHDRAW,
+        recipient: recipient.to_bytes().to_vec(),
+        fee,
+        relayer: request.relayer.clone(),
     }
+    .hash()?;
     let mut encrypted_commitments = request.encrypted_commitments;
-    let public_values_bytes = borsh::to_vec(&PublicValue {
-        root: request.merkle_root,
+    let public_values_bytes = borsh::to_vec(&WithdrawPublicValue {
+        spent_trees: request.spent_trees.clone(),
+        params_hash,
         nullifiers: request.nullifiers.clone(),
         output_hashes: encrypted_commitments.clone(),
+        tree_depth: request.metadata.tree_depth,
+        amount: request.pre_commitments.value,
+        fee,
+        token_id: request.pre_commitments.token_id.clone(),
+        recipient: recipient.to_bytes().to_vec(),
+        relayer: request.relayer.clone(),
     })?;
     
-    // Deserialize the SP1Groth16Proof from the instruction data.
-    let groth16_proof = SP1Groth16Proof {
-        proof: request.proof,
-        sp1_public_inputs: public_values_bytes,
-    };
-    // Create an instruction to invoke the verification program.
-    let instruction = Instruction::new_with_borsh(
-        *verification_program.key,
-        &groth16_proof,
-        vec![],
-    );
-    invoke(&instruction, &[verification_program.clone()])?;
+    verify_proof(
+        verification_program,
+        vk_registry_account,
+        OPERATION_WITHDRAW,
+        request.metadata.circuit_id,
+        request.proof,
+        public_values_bytes,
+    )?;
     msg!("finish verify proof");
     
     encrypted_commitments.pop();
     // ------------------ verify logic end ---------------------- //
-    // check if nullifier already exists
-    for idx in 0..request.nullifiers.len() {
-        if spent_tree.check_nullifier(&request.nullifiers[idx]) {
-            return Err(DarksolError::UtxoAlreadySpent.into());
-        }
-
-        spent_tree.insert_nullifier(request.nullifiers[idx].clone());
+    // mark nullifiers as spent in the nullifier registry, one nullifier
+    // account per nullifier follows the fixed accounts. Fails if any of
+    // them is already spent in any tree
+    for (nullifier, nullifier_account) in request.nullifiers.iter().zip(nullifier_accounts) {
+        insert_nullifier(
+            program_id,
+            &[
+                payer.clone(),
+                nullifier_account.clone(),
+                system_program.clone(),
+            ],
+            nullifier,
+        )?;
     }
 
-    let mut start_position: u64 = spent_tree.next_leaf_index as u64;
-    let mut tree_number: u64 = request.metadata.tree_number;
+    // without new commitments the event points to the first spent tree
+    let mut start_position: u64 = spent_next_leaf_index;
+    let mut tree_number: u64 = request.spent_trees.first().map_or(0, |spent_tree| spent_tree.tree_number);
 
     if !encrypted_commitments.is_empty() {
-        let current_commitment_account = next_account_info(accounts_iter)?; // current tree
+        let commitments = leaves_from_bytes(&encrypted_commitments)?;
+        let current_commitment_account =
+            current_commitment_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
 
         // fetch the current tree number
-        let data = commitments_manager_account.data.borrow();
-        // deserialize the data
-        let manager_data: CommitmentsManagerAccount =
-            CommitmentsManagerAccount::try_from_slice_with_length(&data)?;
+        // the borrow is released right away as a new commitments account
+        // updates the manager
+        let manager_data =
+            CommitmentsManagerAccount::load(&commitments_manager_account.data.borrow())?;
         let current_tree_number = manager_data.incremental_tree_number;
 
-        let mut commitments_acc_data =
-        if current_commitment_account.key == spent_commitments_account.key {
-            &mut spent_commitments_acc_data
-        } else {
-            &mut current_commitment_account.data.borrow_mut()[..]
-        };
-
-        let mut inserted_tree: CommitmentsAccount<TREE_DEPTH> =
-            CommitmentsAccount::try_from_slice_with_length(&commitments_acc_data)?;
+        let mut commitments_acc_data = current_commitment_account.data.borrow_mut();
+        let mut inserted_tree =
+            CommitmentsAccount::load(&mut commitments_acc_data[..])?;
 
         // Derive the PDA for the commitments account
         let (account_pda, _bump_seed) = derive_pda(current_tree_number, program_id);
         // Ensure the provided new_account is the correct PDA
-        if current_commitment_account.key != &account_pda {
-            return Err(ProgramError::InvalidSeeds);
-        }
+        check_address(current_commitment_account, &account_pda)?;
 
         msg!("inserting commitments");
 
         // update merkle tree
         // create new commitments account if insert leaf exceeds max tree depth
         // user should check if the inserted leafs exceeds max tree depth to
-        // add new commitments account, funding account and system program to the instruction
-        if inserted_tree.exceed_tree_depth(1) {
-            let funding_account = next_account_info(accounts_iter)?;
-            let new_commitments_account = next_account_info(accounts_iter)?; // new commitments account
-            let system_program = next_account_info(accounts_iter)?; // System Program for creating accounts
+        // add new commitments account to the instruction
+        if inserted_tree.exceed_tree_depth(commitments.len()) {
+            let new_commitments_account =
+                new_commitments_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
 
             // derive a new commitments account and update the commitments account
             let (new_pda, _bump_seed) = derive_pda(current_tree_number + 1, program_id);
+            check_address(new_commitments_account, &new_pda)?;
 
-            if new_commitments_account.key != &new_pda {
-                return Err(ProgramError::InvalidSeeds);
-            }
-
-            inserted_tree = initialize_commitments_account(
+            initialize_commitments_account(
                 program_id,
                 &[
                     funding_account.clone(),
@@ -678,53 +1020,77 @@ pub fn process_withdraw_asset(
                 ],
             )?;
 
-            let mut new_commitments_data = &mut new_commitments_account.data.borrow_mut()[..];
+            let mut new_commitments_data = new_commitments_account.data.borrow_mut();
+            let mut new_tree =
+                CommitmentsAccount::load(&mut new_commitments_data[..])?;
 
             // insert leaf into tree
-            match inserted_tree.insert_commitments(&mut encrypted_commitments, &mut new_commitments_data) {
-                Ok(next_leaf_index) => {
-                    tree_number = current_tree_number + 1;
-                    start_position = next_leaf_index;
-                }
-                Err(_err) => return Err(DarksolError::FailedInsertCommitmentHash.into()),
-            }
+            new_tree.hash_function()?.check_leaves(&commitments)?;
+            let insert_result = new_tree
+                .insert_commitments(&commitments)
+                .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
+            tree_number = current_tree_number + 1;
+            start_position = insert_result.first_index;
         } else {
             // insert leaf into tree
-            match inserted_tree.insert_commitments(&mut encrypted_commitments, &mut commitments_acc_data) {
-                Ok(next_leaf_index) => {
-                    tree_number = current_tree_number;
-                    start_position = next_leaf_index;
-                    msg!("start position: {:?}", start_position);
-                }
-                Err(_err) => return Err(DarksolError::FailedInsertCommitmentHash.into()),
-            }
+            inserted_tree.hash_function()?.check_leaves(&commitments)?;
+            let insert_result = inserted_tree
+                .insert_commitments(&commitments)
+                .map_err(|_| DarksolError::FailedInsertCommitmentHash)?;
+            tree_number = current_tree_number;
+            start_position = insert_result.first_index;
+            msg!("start position: {:?}", start_position);
         }
     }
 
-    // update nullifiers list
-    spent_tree.serialize_with_length(&mut spent_commitments_acc_data)?;
-
     // transfer token to reciever token account
     // TODO: test and fix transfer_token_out
-    transfer_token_out(
-        program_id,
-        &[
-            funding_account.clone(),
-            user_wallet.clone(),
-            user_token_account.clone(),
-            pda_token_account.clone(),
-            token_program.clone(),
-        ],
-        request.pre_commitments.value,
-    )?;
+    if let (Some(user_token_account), Some(token_program)) = (user_token_account, token_program) {
+        transfer_token_out(
+            program_id,
+            &[
+                funding_account.clone(),
+                user_wallet.clone(),
+                user_token_account.clone(),
+                vault_account.clone(),
+                token_program.clone(),
+                treasury_account.clone(),
+            ],
+            &request.pre_commitments.token_id,
+            recipient_amount,
+            fee,
+        )?;
+    } else {
+        transfer_native_out(
+            program_id,
+            &[
+                vault_account.clone(),
+                user_wallet.clone(),
+                treasury_account.clone(),
+            ],
+            recipient_amount,
+            fee,
+        )?;
+    }
+
+    if let (Some(relayer), Some(relayer_account)) = (&request.relayer, relayer_account) {
+        let relayer_accounts = RelayerAccounts {
+            relayer: relayer_account,
+            vault: vault_account,
+            token_program,
+        };
+        pay_relayer(program_id, funding_account, &relayer_accounts, relayer)?;
+    }
 
     msg!("transfered to user token account");
     // emit event
-    let event = TransactionEvent {
+    let event = WithdrawEvent {
         start_position,
         tree_number,
         commitments: encrypted_commitments,
         commitment_cipher_text: request.commitment_cipher_texts,
+        amount: request.pre_commitments.value,
+        fee,
     };
 
     let serialize_event = borsh::to_vec(&event)?;
@@ -739,8 +1105,89 @@ pub fn process_withdraw_asset(
     Ok(())
 }
 
-pub fn process_initialize_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+pub fn process_initialize_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    root_history_size: u32,
+) -> ProgramResult {
     msg!("Hello");
-    initialize_commitments_manager(program_id, accounts)?;
+    validate_root_history_size(root_history_size)?;
+
+    let InitializeAccounts {
+        payer: payer_account,
+        funding: funding_account,
+        commitments: commitments_account,
+        commitments_manager: commitments_manager_account,
+        system_program,
+        config: config_account,
+        program_data: program_data_account,
+        verification_program,
+    } = InitializeAccounts::parse(program_id, accounts)?;
+
+    // only the upgrade authority can initialize the program
+    check_upgrade_authority(program_id, program_data_account, payer_account)?;
+
+    initialize_commitments_manager(
+        program_id,
+        &[
+            payer_account.clone(),
+            funding_account.clone(),
+            commitments_account.clone(),
+            commitments_manager_account.clone(),
+            system_program.clone(),
+        ],
+        root_history_size,
+    )?;
+    initialize_config(
+        program_id,
+        &[
+            payer_account.clone(),
+            config_account.clone(),
+            system_program.clone(),
+        ],
+        payer_account.key,
+        verification_program.key,
+    )?;
+    Ok(())
+}
+
+pub fn process_migrate_tree(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+) -> ProgramResult {
+    migrate_commitments_account(program_id, accounts, tree_number)?;
+    Ok(())
+}
+
+pub fn process_migrate_nullifiers(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+) -> ProgramResult {
+    migrate_legacy_nullifiers(program_id, accounts, tree_number)?;
+    Ok(())
+}
+
+pub fn process_extend_tree(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+    additional_roots: u32,
+) -> ProgramResult {
+    extend_commitments_account(program_id, accounts, tree_number, additional_roots)?;
+    Ok(())
+}
+
+pub fn process_verify_inclusion(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+    leaf: [u8; 32],
+    index: u64,
+    path: Vec<[u8; 32]>,
+    root: [u8; 32],
+) -> ProgramResult {
+    verify_commitment_inclusion(program_id, accounts, tree_number, leaf, index, path, root)?;
     Ok(())
 }
@@ -1,8 +1,15 @@
-use crate::merkle::CommitmentsAccount;
+use crate::accounts::{check_address, check_owner, check_signer, check_system_program, check_writable};
+use crate::error::DarksolError;
+use crate::merkle::{
+    commitments_account_space, legacy_root_history_size, CommitmentsAccount, HashFunction,
+    LegacyCommitmentsAccount, COMMITMENTS_ACCOUNT_TAG,
+};
+use crate::nullifier::{derive_nullifier_pda, insert_legacy_nullifier, is_nullifier_spent};
+use crate::utils::account::create_pda_account_from_pda_account;
 use crate::utils::serialize::{
     BorshDeserializeWithLength, BorshSerializeWithLength, DATA_LENGTH_CAPACITY,
 };
-use crate::{derive_pda, TREE_DEPTH};
+use crate::{derive_pda, DEFAULT_ROOT_HISTORY_SIZE, LEGACY_TREE_DEPTH, TREE_DEPTH};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::program::invoke;
 use solana_program::{
@@ -11,15 +18,80 @@ use solana_program::{
     program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
-    system_instruction,
     sysvar::{rent::Rent, Sysvar},
 };
+use solana_system_interface::instruction as system_instruction;
 
 // CommitmentsManagerAccount is a single account
 // tracks all the commitments accounts by their tree number
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct CommitmentsManagerAccount {
     pub incremental_tree_number: u64,
+    pub root_history_size: u32, // root history capacity of newly created trees
+    pub tree_depth: u8,         // depth of newly created trees
+    pub hash_function: u8,      // HashFunction of newly created trees
+}
+
+// COMMITMENTS_MANAGER_ACCOUNT_SPACE is the size of the commitments manager
+// account in the current layout
+pub const COMMITMENTS_MANAGER_ACCOUNT_SPACE: usize = 8 + 4 + 1 + 1 + DATA_LENGTH_CAPACITY;
+
+// COMMITMENTS_MANAGER_V0_LENGTH is the payload length of the deployed
+// commitments manager, which only holds the tree number
+const COMMITMENTS_MANAGER_V0_LENGTH: usize = 8;
+
+impl CommitmentsManagerAccount {
+    // load read the commitments manager in the current layout or the
+    // deployed layout. Fields missing from the deployed layout take the
+    // values trees were created with at the time, SHA-256 trees of
+    // LEGACY_TREE_DEPTH with the default root history
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        let length_bytes = data
+            .get(..DATA_LENGTH_CAPACITY)
+            .ok_or(DarksolError::UnsupportedAccountVersion)?;
+        let length = u64::from_le_bytes(length_bytes.try_into().unwrap()) as usize;
+        let payload = data
+            .get(DATA_LENGTH_CAPACITY..DATA_LENGTH_CAPACITY.saturating_add(length))
+            .ok_or(DarksolError::UnsupportedAccountVersion)?;
+
+        let manager = match length {
+            COMMITMENTS_MANAGER_V0_LENGTH => Self {
+                incremental_tree_number: u64::try_from_slice(payload)?,
+                root_history_size: DEFAULT_ROOT_HISTORY_SIZE,
+                tree_depth: LEGACY_TREE_DEPTH as u8,
+                hash_function: HashFunction::Sha256 as u8,
+            },
+            _ => Self::try_from_slice(payload)
+                .map_err(|_| DarksolError::UnsupportedAccountVersion)?,
+        };
+
+        Ok(manager)
+    }
+
+    // save write the commitments manager in the current layout. A manager
+    // created with the deployed layout is grown first, the funding account
+    // tops up its rent
+    pub fn save(
+        &self,
+        commitments_manager_account: &AccountInfo,
+        funding_account: Option<&AccountInfo>,
+    ) -> Result<(), ProgramError> {
+        if commitments_manager_account.data_len() < COMMITMENTS_MANAGER_ACCOUNT_SPACE {
+            let funding_account = funding_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            commitments_manager_account.realloc(COMMITMENTS_MANAGER_ACCOUNT_SPACE, false)?;
+
+            let required_lamports = Rent::get()?
+                .minimum_balance(COMMITMENTS_MANAGER_ACCOUNT_SPACE)
+                .saturating_sub(commitments_manager_account.lamports());
+            if required_lamports > 0 {
+                **funding_account.try_borrow_mut_lamports()? -= required_lamports;
+                **commitments_manager_account.try_borrow_mut_lamports()? += required_lamports;
+            }
+        }
+
+        self.serialize_with_length(&mut &mut commitments_manager_account.data.borrow_mut()[..])?;
+        Ok(())
+    }
 }
 
 // initialize_commitments_manager create a new commiments manager account
@@ -29,6 +101,7 @@ pub struct CommitmentsManagerAccount {
 pub fn initialize_commitments_manager(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
+    root_history_size: u32,
 ) -> Result<(), ProgramError> {
     msg!("1");
 
@@ -57,9 +130,7 @@ pub fn initialize_commitments_manager(
     // TODO: change the seeds
     let (commitments_manager_pda, commitments_manager_bump_seed) =
         Pubkey::find_program_address(&[b"commitments_manager_pda"], program_id);
-    if commitments_manager_account.key != &commitments_manager_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(commitments_manager_account, &commitments_manager_pda)?;
 
     // // account should only initialized once
     // if !commitments_manager_account.data_is_empty() {
@@ -69,13 +140,11 @@ pub fn initialize_commitments_manager(
     // Derive the PDA for the newly account
     let (account_pda, bump_seed) = derive_pda(1, program_id);
     // Ensure the provided new_account is the correct PDA
-    if commitments_account.key != &account_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(commitments_account, &account_pda)?;
     msg!("2");
 
     // Size of our commitments manager account
-    let manager_account_space: usize = 8 + DATA_LENGTH_CAPACITY;
+    let manager_account_space: usize = COMMITMENTS_MANAGER_ACCOUNT_SPACE;
 
     // Calculate minimum balance for rent exemption
     let manager_account_rent = Rent::get()?;
@@ -110,7 +179,7 @@ pub fn initialize_commitments_manager(
     // Create the commitments manager account
     invoke_signed(
         &system_instruction::create_account(
-            &payer_account.key,                // Account paying for the new account
+            payer_account.key,                 // Account paying for the new account
             &commitments_manager_pda,          // Account to be created
             manager_account_required_lamports, // Amount of lamports to transfer to the new account
             manager_account_space as u64,      // Size in bytes to allocate for the data field
@@ -127,8 +196,7 @@ pub fn initialize_commitments_manager(
     msg!("3");
 
     // Size of our commitments account
-    // set to maximum
-    let account_space = 10240;
+    let account_space = commitments_account_space(root_history_size.max(1) as usize);
 
     // Calculate minimum balance for rent exemption
     let rent = Rent::get()?;
@@ -137,7 +205,7 @@ pub fn initialize_commitments_manager(
     // Create the commitments account
     invoke_signed(
         &system_instruction::create_account(
-            &payer_account.key,   // Account paying for the new account
+            payer_account.key,    // Account paying for the new account
             &account_pda,         // Account to be created
             required_lamports,    // Amount of lamports to transfer to the new account
             account_space as u64, // Size in bytes to allocate for the data field
@@ -154,6 +222,9 @@ pub fn initialize_commitments_manager(
     // Update incremental to 2 as we also create a new empty tree
     let new_manager_data = CommitmentsManagerAccount {
         incremental_tree_number: 1,
+        root_history_size,
+        tree_depth: TREE_DEPTH as u8,
+        hash_function: HashFunction::Sha256 as u8,
     };
     new_manager_data.serialize_with_length(&mut &mut commitments_manager_account.data.borrow_mut()[..])?;
     msg!(
@@ -161,11 +232,14 @@ pub fn initialize_commitments_manager(
         2
     );
 
-    // store empty tree to the newly created commitments account
-    let new_empty_tree: CommitmentsAccount<TREE_DEPTH> = CommitmentsAccount::new(1);
-
-    // Serialize the struct into the account's data
-    new_empty_tree.serialize_with_length(&mut &mut commitments_account.data.borrow_mut()[..])?;
+    // write empty tree to the newly created commitments account
+    CommitmentsAccount::initialize(
+        &mut commitments_account.data.borrow_mut()[..],
+        1,
+        TREE_DEPTH,
+        root_history_size.max(1) as usize,
+        HashFunction::Sha256,
+    )?;
 
     msg!("commitments initialized");
 
@@ -179,7 +253,7 @@ pub fn initialize_commitments_manager(
 pub fn initialize_commitments_account(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-) -> Result<CommitmentsAccount<TREE_DEPTH>, ProgramError> {
+) -> Result<(), ProgramError> {
     let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
 
     let funding_account = next_account_info(accounts_iter)?;
@@ -187,72 +261,319 @@ pub fn initialize_commitments_account(
     let commitments_mananger_account = next_account_info(accounts_iter)?;
     let system_program = next_account_info(accounts_iter)?;
 
-    if commitments_account.owner != program_id || commitments_mananger_account.owner != program_id {
-        return Err(ProgramError::IncorrectProgramId);
-    }
+    // the new commitments account is still owned by the system program
+    check_owner(funding_account, program_id)?;
+    check_owner(commitments_mananger_account, program_id)?;
 
     // Derive PDA funding account to pay for the new account
     // TODO: change the seeds
-    let (funding_pda, funding_bump_seed) =
+    let (funding_pda, _funding_bump_seed) =
         Pubkey::find_program_address(&[b"funding_pda"], program_id);
-    if funding_account.key != &funding_pda {
-        return Err(ProgramError::InvalidSeeds);
-    }
+    check_address(funding_account, &funding_pda)?;
 
     // fetch the new tree number to derive newly account
-    //
-    // Mutable borrow the commitments manager account data
-    let mut data = commitments_mananger_account.data.borrow_mut();
-    // deserialize the data
-    let mut manager_data: CommitmentsManagerAccount =
-        CommitmentsManagerAccount::try_from_slice_with_length(&data)?;
+    let mut manager_data =
+        CommitmentsManagerAccount::load(&commitments_mananger_account.data.borrow())?;
     let new_tree_number = manager_data.incremental_tree_number + 1;
 
     // Derive the PDA for the newly account
-    let (account_pda, _bump_seed) = derive_pda(new_tree_number, program_id);
+    let (account_pda, bump_seed) = derive_pda(new_tree_number, program_id);
     // Ensure the provided new_account is the correct PDA
-    if commitments_account.key != &account_pda {
-        return Err(ProgramError::InvalidSeeds);
+    check_address(commitments_account, &account_pda)?;
+
+    // Size of our commitments account, only the configured root
+    // history is allocated. Use extend tree instruction to grow it
+    let root_history_size = manager_data.root_history_size.max(1) as usize;
+    let account_space = commitments_account_space(root_history_size);
+
+    // the funding account is program owned so it can not be the source of
+    // a system create account, allocate and assign the PDA then fund it
+    create_pda_account_from_pda_account(
+        funding_account,
+        account_space,
+        program_id,
+        system_program,
+        commitments_account,
+        &[&new_tree_number.to_le_bytes(), &[bump_seed]],
+    )?;
+
+    manager_data.incremental_tree_number = new_tree_number;
+    manager_data.save(commitments_mananger_account, Some(funding_account))?;
+
+    msg!("adding new commitment account to manager");
+
+    // write empty tree to the newly created commitments account
+    CommitmentsAccount::initialize(
+        &mut commitments_account.data.borrow_mut()[..],
+        new_tree_number,
+        manager_data.tree_depth as usize,
+        root_history_size,
+        HashFunction::try_from(manager_data.hash_function)?,
+    )?;
+
+    msg!("commitments initialized");
+
+    Ok(())
+}
+
+// migrated_root_history_size returns the root history size of a migrated
+// legacy tree, large enough to keep every legacy root valid
+fn migrated_root_history_size(legacy_tree: &LegacyCommitmentsAccount) -> usize {
+    legacy_root_history_size(legacy_tree).max(DEFAULT_ROOT_HISTORY_SIZE as usize)
+}
+
+// migrate_commitments_account rewrite a commitments account stored in
+// the legacy borsh layout to the zero-copy layout. The account is resized
+// if needed and the funding account tops up rent
+pub fn migrate_commitments_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+) -> Result<(), ProgramError> {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let funding_account = next_account_info(accounts_iter)?;
+    let commitments_account = next_account_info(accounts_iter)?;
+
+    check_owner(commitments_account, program_id)?;
+
+    check_owner(funding_account, program_id)?;
+
+    let (funding_pda, _funding_bump_seed) =
+        Pubkey::find_program_address(&[b"funding_pda"], program_id);
+    check_address(funding_account, &funding_pda)?;
+
+    let (account_pda, _bump_seed) = derive_pda(tree_number, program_id);
+    check_address(commitments_account, &account_pda)?;
+
+    // read the legacy tree
+    let legacy_tree = {
+        let data = commitments_account.data.borrow();
+        if data.len() >= COMMITMENTS_ACCOUNT_TAG.len()
+            && data[..COMMITMENTS_ACCOUNT_TAG.len()] == COMMITMENTS_ACCOUNT_TAG
+        {
+            return Err(DarksolError::AccountAlreadyMigrated.into());
+        }
+
+        LegacyCommitmentsAccount::try_from_slice_with_length(&data)
+            .map_err(|_| DarksolError::InvalidCommitmentsAccount)?
+    };
+    if legacy_tree.tree_number != tree_number {
+        return Err(DarksolError::InvalidCommitmentsAccount.into());
     }
 
-    // Size of our commitments account
-    // set to maximum
-    let account_space = 10_485_760;
+    // the spent nullifiers must be moved to the nullifier registry
+    // first or their notes could be spent again
+    if !legacy_tree.nullifiers.is_empty() {
+        return Err(DarksolError::NullifiersNotMigrated.into());
+    }
 
-    // Calculate minimum balance for rent exemption
+    let root_history_size = migrated_root_history_size(&legacy_tree);
+    let account_space = commitments_account_space(root_history_size);
+
+    if commitments_account.data_len() < account_space {
+        commitments_account.realloc(account_space, false)?;
+    }
+
+    // keep the account rent exempt for its current size
     let rent = Rent::get()?;
-    let required_lamports = rent.minimum_balance(account_space);
+    let required_lamports = rent
+        .minimum_balance(commitments_account.data_len())
+        .saturating_sub(commitments_account.lamports());
+    if required_lamports > 0 {
+        **funding_account.try_borrow_mut_lamports()? -= required_lamports;
+        **commitments_account.try_borrow_mut_lamports()? += required_lamports;
+    }
 
-    // Create the commitments account
-    invoke_signed(
-        &system_instruction::create_account(
-            funding_account.key,  // Account paying for the new account
-            &account_pda,         // Account to be created
-            required_lamports,    // Amount of lamports to transfer to the new account
-            account_space as u64, // Size in bytes to allocate for the data field
-            program_id,           // Set program owner to our program
-        ),
-        &[
-            funding_account.clone(),
-            commitments_account.clone(),
-            system_program.clone(),
-        ],
-        &[&[b"funding_pda", &[funding_bump_seed]]],
-    )?;
+    let mut data = commitments_account.data.borrow_mut();
+    let tree =
+        CommitmentsAccount::migrate(&mut data[..account_space], &legacy_tree, root_history_size)?;
+    if tree.tree_number() != tree_number {
+        return Err(DarksolError::InvalidCommitmentsAccount.into());
+    }
 
-    manager_data.incremental_tree_number = new_tree_number;
-    // Serialize the CounterAccount struct into the account's data
-    manager_data.serialize_with_length(&mut &mut data[..])?;
+    msg!("commitments account {} migrated", tree_number);
 
-    msg!("adding new commitment account to manager");
+    Ok(())
+}
 
-    // store empty tree to the newly created commitments account
-    let new_empty_tree: CommitmentsAccount<TREE_DEPTH> =
-        CommitmentsAccount::new(new_tree_number as u64);
-    // Serialize the struct into the account's data
-    new_empty_tree.serialize_with_length(&mut &mut commitments_account.data.borrow_mut()[..])?;
+// migrate_legacy_nullifiers move the spent nullifiers of a legacy
+// commitments account to the nullifier registry. The nullifier accounts
+// follow the system program and take the legacy nullifiers in ascending
+// order, so a large map can be moved over several instructions. Nullifiers
+// already in the registry are only dropped from the legacy map
+pub fn migrate_legacy_nullifiers(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+) -> Result<(), ProgramError> {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
 
-    msg!("commitments initialized");
+    let funding_account = next_account_info(accounts_iter)?;
+    let commitments_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let nullifier_accounts = accounts_iter.as_slice();
+
+    check_system_program(system_program)?;
+    check_owner(funding_account, program_id)?;
+    check_owner(commitments_account, program_id)?;
+    check_writable(commitments_account)?;
+
+    let (funding_pda, _funding_bump_seed) =
+        Pubkey::find_program_address(&[b"funding_pda"], program_id);
+    check_address(funding_account, &funding_pda)?;
+
+    let (account_pda, _bump_seed) = derive_pda(tree_number, program_id);
+    check_address(commitments_account, &account_pda)?;
+
+    let mut legacy_tree = {
+        let data = commitments_account.data.borrow();
+        if data.len() >= COMMITMENTS_ACCOUNT_TAG.len()
+            && data[..COMMITMENTS_ACCOUNT_TAG.len()] == COMMITMENTS_ACCOUNT_TAG
+        {
+            return Err(DarksolError::AccountAlreadyMigrated.into());
+        }
+        LegacyCommitmentsAccount::try_from_slice_with_length(&data)
+            .map_err(|_| DarksolError::InvalidCommitmentsAccount)?
+    };
+    if legacy_tree.tree_number != tree_number {
+        return Err(DarksolError::InvalidCommitmentsAccount.into());
+    }
+
+    let mut nullifiers: Vec<Vec<u8>> = legacy_tree.nullifiers.keys().cloned().collect();
+    nullifiers.sort();
+    if nullifier_accounts.is_empty() || nullifier_accounts.len() > nullifiers.len() {
+        return Err(DarksolError::InvalidInstructionData.into());
+    }
+
+    for (nullifier, nullifier_account) in nullifiers.iter().zip(nullifier_accounts.iter()) {
+        let (nullifier_pda, _nullifier_bump_seed) = derive_nullifier_pda(nullifier, program_id);
+        check_address(nullifier_account, &nullifier_pda)?;
+
+        if !is_nullifier_spent(program_id, nullifier_account) {
+            insert_legacy_nullifier(
+                program_id,
+                &[funding_account.clone(), nullifier_account.clone(), system_program.clone()],
+                nullifier,
+            )?;
+        }
+        legacy_tree.nullifiers.remove(nullifier);
+    }
+
+    // the legacy layout is length prefixed, the shorter map leaves
+    // unread bytes at the end of the account
+    legacy_tree.serialize_with_length(&mut &mut commitments_account.data.borrow_mut()[..])?;
+
+    msg!(
+        "moved {} nullifiers of commitments account {}, {} left",
+        nullifier_accounts.len(),
+        tree_number,
+        legacy_tree.nullifiers.len()
+    );
+
+    Ok(())
+}
+
+// extend_commitments_account grow the root history of a commitments
+// account by `additional_roots`. Anyone can call it, the payer pays
+// the rent of the added space
+pub fn extend_commitments_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+    additional_roots: u32,
+) -> Result<(), ProgramError> {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
+
+    let payer_account = next_account_info(accounts_iter)?;
+    let commitments_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    check_system_program(system_program)?;
+
+    check_owner(commitments_account, program_id)?;
+
+    check_signer(payer_account)?;
+
+    let (account_pda, _bump_seed) = derive_pda(tree_number, program_id);
+    check_address(commitments_account, &account_pda)?;
+
+    if additional_roots == 0 {
+        return Err(DarksolError::InvalidInstructionData.into());
+    }
+
+    let root_history_size = {
+        let data = commitments_account.data.borrow();
+        CommitmentsAccount::load(&data[..])?.root_history_size()
+    };
+    let new_root_history_size = root_history_size
+        .checked_add(additional_roots as usize)
+        .filter(|size| *size <= u32::MAX as usize)
+        .ok_or(DarksolError::InvalidInstructionData)?;
+    let account_space = commitments_account_space(new_root_history_size);
+
+    // pay rent for the new size
+    let rent = Rent::get()?;
+    let required_lamports = rent
+        .minimum_balance(account_space)
+        .saturating_sub(commitments_account.lamports());
+    if required_lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer_account.key, commitments_account.key, required_lamports),
+            &[
+                payer_account.clone(),
+                commitments_account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+    }
+
+    // realloc is limited to MAX_PERMITTED_DATA_INCREASE per instruction
+    if commitments_account.data_len() < account_space {
+        commitments_account.realloc(account_space, false)?;
+    }
+
+    CommitmentsAccount::load(&mut commitments_account.data.borrow_mut()[..])?
+        .extend_root_history(additional_roots as usize)?;
+
+    msg!(
+        "commitments account {} root history extended to {}",
+        tree_number,
+        new_root_history_size
+    );
+
+    Ok(())
+}
+
+// verify_commitment_inclusion checks the commitment `leaf` is the leaf at
+// `index` of the tree under `root`, one of its latest roots. It only
+// reads the tree and fails if the commitment is not included, so a
+// deposit can be proven without trusting an indexer
+pub fn verify_commitment_inclusion(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tree_number: u64,
+    leaf: [u8; 32],
+    index: u64,
+    path: Vec<[u8; 32]>,
+    root: [u8; 32],
+) -> Result<(), ProgramError> {
+    let accounts_iter: &mut std::slice::Iter<'_, _> = &mut accounts.iter();
 
-    Ok(new_empty_tree)
+    let commitments_account = next_account_info(accounts_iter)?;
+
+    check_owner(commitments_account, program_id)?;
+
+    let (account_pda, _bump_seed) = derive_pda(tree_number, program_id);
+    check_address(commitments_account, &account_pda)?;
+
+    let data = commitments_account.data.borrow();
+    let tree = CommitmentsAccount::load(&data[..])?;
+    if !tree.verify_inclusion(&leaf, index, &path, &root)? {
+        return Err(DarksolError::InvalidInclusionProof.into());
+    }
+
+    msg!("commitment {} included in tree {}", index, tree_number);
+
+    Ok(())
 }
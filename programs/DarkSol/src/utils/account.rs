@@ -1,7 +1,11 @@
 use solana_program::{
-    account_info::AccountInfo, msg, program::invoke_signed, program_error::ProgramError,
-    pubkey::Pubkey, rent::Rent, system_instruction, sysvar::Sysvar,
+    account_info::AccountInfo,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey, rent::Rent, sysvar::Sysvar,
 };
+use solana_system_interface::instruction as system_instruction;
 
 pub fn create_pda_account_from_pda_account<'a>(
     from_account: &AccountInfo<'a>,
@@ -16,7 +20,7 @@ pub fn create_pda_account_from_pda_account<'a>(
     let required_lamports = rent
         .minimum_balance(space)
         .max(1)
-        .saturating_sub(from_account.lamports());
+        .saturating_sub(new_pda_account.lamports());
 
     msg!("Allocating PDA");
     invoke_signed(
@@ -41,6 +45,45 @@ pub fn create_pda_account_from_pda_account<'a>(
     Ok(())
 }
 
+// create_pda_account_from_payer creates a program owned PDA paid by a
+// system account signing the transaction. The account is allocated and
+// assigned instead of created, lamports sent to the PDA beforehand only
+// lower the rent to pay and can not block its creation
+pub fn create_pda_account_from_payer<'a>(
+    payer_account: &AccountInfo<'a>,
+    space: usize,
+    owner: &Pubkey,
+    system_program: &AccountInfo<'a>,
+    new_pda_account: &AccountInfo<'a>,
+    new_pda_signer_seeds: &[&[u8]],
+) -> Result<(), ProgramError> {
+    let rent: &Rent = &Rent::get()?;
+
+    let required_lamports = rent
+        .minimum_balance(space)
+        .max(1)
+        .saturating_sub(new_pda_account.lamports());
+
+    if required_lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer_account.key, new_pda_account.key, required_lamports),
+            &[payer_account.clone(), new_pda_account.clone(), system_program.clone()],
+        )?;
+    }
+
+    invoke_signed(
+        &system_instruction::allocate(new_pda_account.key, space.try_into().unwrap()),
+        &[new_pda_account.clone(), system_program.clone()],
+        &[new_pda_signer_seeds],
+    )?;
+
+    invoke_signed(
+        &system_instruction::assign(new_pda_account.key, owner),
+        &[new_pda_account.clone(), system_program.clone()],
+        &[new_pda_signer_seeds],
+    )
+}
+
 pub fn get_associated_token_address_and_bump_seed(
     wallet_address: &Pubkey,
     token_mint_address: &Pubkey,
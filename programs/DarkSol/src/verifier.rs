@@ -0,0 +1,82 @@
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke,
+};
+
+use crate::{SP1Groth16Proof, VERIFY_INSTRUCTION};
+
+#[cfg(feature = "in-process-verification")]
+use verify_proof_in_process as verify_groth16_proof;
+#[cfg(not(feature = "in-process-verification"))]
+use verify_proof_cpi as verify_groth16_proof;
+
+// verify_proof verifies the proof with the verification keys of the
+// registered circuit it targets. Each operation has its own circuits so a
+// proof of another operation is rejected. The proof is verified inside the
+// program with the in-process-verification feature, otherwise through a CPI
+// into the verification program
+pub fn verify_proof<'a>(
+    verification_program: &AccountInfo<'a>,
+    vk_registry_account: &AccountInfo<'a>,
+    operation: u8,
+    circuit_id: u32,
+    proof: Vec<u8>,
+    sp1_public_inputs: Vec<u8>,
+) -> ProgramResult {
+    let groth16_proof = SP1Groth16Proof {
+        operation,
+        circuit_id,
+        proof,
+        sp1_public_inputs,
+    };
+
+    verify_groth16_proof(verification_program, vk_registry_account, groth16_proof)
+}
+
+// verify_proof_cpi invokes the verification program to verify the proof
+pub fn verify_proof_cpi<'a>(
+    verification_program: &AccountInfo<'a>,
+    vk_registry_account: &AccountInfo<'a>,
+    groth16_proof: SP1Groth16Proof,
+) -> ProgramResult {
+    // Create an instruction to invoke the verification program.
+    let instruction = Instruction::new_with_borsh(
+        *verification_program.key,
+        &(VERIFY_INSTRUCTION, groth16_proof),
+        vec![AccountMeta::new_readonly(*vk_registry_account.key, false)],
+    );
+    invoke(
+        &instruction,
+        &[vk_registry_account.clone(), verification_program.clone()],
+    )
+}
+
+// verify_proof_in_process verifies the proof with the verifier linked as a
+// library, saving the CPI. The verification keys are still read from the
+// registry of the verification program so both paths accept the same circuits
+#[cfg(feature = "in-process-verification")]
+pub fn verify_proof_in_process(
+    verification_program: &AccountInfo,
+    vk_registry_account: &AccountInfo,
+    groth16_proof: SP1Groth16Proof,
+) -> ProgramResult {
+    use verification::{
+        registry::load_vk_registry, utils::VerificationError, verify_proof::verify_proof_with_hash,
+    };
+
+    let registry = load_vk_registry(verification_program.key, vk_registry_account)?;
+    let circuit = registry
+        .circuit(groth16_proof.operation, groth16_proof.circuit_id)
+        .ok_or(VerificationError::UnknownCircuit)?;
+
+    verify_proof_with_hash(
+        &groth16_proof.proof,
+        &groth16_proof.sp1_public_inputs,
+        &circuit.sp1_vkey_hash,
+        &circuit.groth16_vk,
+    )?;
+
+    Ok(())
+}
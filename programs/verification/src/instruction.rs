@@ -0,0 +1,52 @@
+//! Instructions of the verification program.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::pubkey::Pubkey;
+
+use crate::registry::CircuitVk;
+use crate::utils::VerificationError;
+
+/// The instruction data for the program, the first byte is the variant.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub enum VerificationInstruction {
+    /// Verifies a proof against the registered circuit of the operation it targets.
+    ///
+    /// Accounts: registry.
+    Verify { proof: SP1Groth16Proof },
+    /// Creates the verification key registry.
+    ///
+    /// Accounts: payer ( upgrade authority ), registry, system program, program data.
+    InitializeRegistry,
+    /// Registers or replaces the verification keys of a circuit.
+    ///
+    /// Accounts: admin, registry, system program.
+    SetCircuit { circuit: CircuitVk },
+    /// Removes a registered circuit of an operation.
+    ///
+    /// Accounts: admin, registry, system program.
+    RemoveCircuit { operation: u8, circuit_id: u32 },
+    /// Hands the registry admin role over.
+    ///
+    /// Accounts: admin, registry, system program.
+    SetAdmin { new_admin: Pubkey },
+    /// Verifies a batch of proofs with a single random linear combination pairing check, all
+    /// proofs must target the same circuit.
+    ///
+    /// Accounts: registry.
+    VerifyBatch { proofs: Vec<SP1Groth16Proof> },
+}
+
+impl VerificationInstruction {
+    pub fn unpack(input: &[u8]) -> Result<Self, VerificationError> {
+        Self::try_from_slice(input).map_err(|_| VerificationError::InvalidInstructionData)
+    }
+}
+
+/// A SP1 Groth16 proof and the operation and circuit version it targets.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct SP1Groth16Proof {
+    pub operation: u8,
+    pub circuit_id: u32,
+    pub proof: Vec<u8>,
+    pub sp1_public_inputs: Vec<u8>,
+}
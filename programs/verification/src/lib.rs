@@ -1,19 +1,22 @@
-use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
-    pubkey::Pubkey,
+    account_info::{next_account_info, AccountInfo}, entrypoint::ProgramResult, pubkey::Pubkey,
 };
 use utils::VerificationError;
-use veil_types::SP1Groth16Proof;
 
 pub mod verify_proof;
-use verify_proof::verify_proof;
+use verify_proof::{verify_proof_batch, verify_proof_with_hash};
 pub mod utils;
+pub mod registry;
+use registry::{initialize_vk_registry, load_vk_registry, remove_circuit, set_admin, set_circuit};
+pub mod instruction;
+use instruction::{SP1Groth16Proof, VerificationInstruction};
 
 #[cfg(not(feature = "no-entrypoint"))]
 solana_program::entrypoint!(process_instruction);
 
 #[cfg(not(doctest))]
+/// The vkey hash of the current circuit, registered in the verification key registry.
+///
 /// Derived as follows:
 ///
 /// ```
@@ -21,27 +24,86 @@ solana_program::entrypoint!(process_instruction);
 /// let (pk, vk) = client.setup(YOUR_ELF_HERE);
 /// let vkey_hash = vk.bytes32();
 /// ```
-const METHOD_VKEY_HASH: &str =
+pub const METHOD_VKEY_HASH: &str =
     "0x00ce4403d3b7f80a5787cf33d4809b5c0ddabf363c8375afd3822fdedc593cbe";
 
 pub fn process_instruction(
-    _program_id: &Pubkey,
-    _accounts: &[AccountInfo],
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    // Deserialize the SP1Groth16Proof from the instruction data.
-    let groth16_proof = SP1Groth16Proof::try_from_slice(instruction_data)
-        .map_err(|_| VerificationError::InvalidInstructionData)?;
+    match VerificationInstruction::unpack(instruction_data)? {
+        VerificationInstruction::Verify { proof } => process_verify(program_id, accounts, proof),
+        VerificationInstruction::InitializeRegistry => initialize_vk_registry(program_id, accounts),
+        VerificationInstruction::SetCircuit { circuit } => set_circuit(program_id, accounts, circuit),
+        VerificationInstruction::RemoveCircuit { operation, circuit_id } => {
+            remove_circuit(program_id, accounts, operation, circuit_id)
+        }
+        VerificationInstruction::SetAdmin { new_admin } => set_admin(program_id, accounts, new_admin),
+        VerificationInstruction::VerifyBatch { proofs } => {
+            process_verify_batch(program_id, accounts, proofs)
+        }
+    }
+}
+
+/// Verifies the proof with the verification keys of the circuit it targets, each operation
+/// dispatches to its own circuits.
+fn process_verify(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    groth16_proof: SP1Groth16Proof,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let registry_account = next_account_info(accounts_iter)?;
 
-    // Get the SP1 Groth16 verification key from the `sp1-solana` crate.
-    let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+    let registry = load_vk_registry(program_id, registry_account)?;
+    let circuit = registry
+        .circuit(groth16_proof.operation, groth16_proof.circuit_id)
+        .ok_or(VerificationError::UnknownCircuit)?;
 
     // Verify the proof.
-    verify_proof(
+    verify_proof_with_hash(
         &groth16_proof.proof,
         &groth16_proof.sp1_public_inputs,
-        &METHOD_VKEY_HASH,
-        vk,
+        &circuit.sp1_vkey_hash,
+        &circuit.groth16_vk,
     )?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Verifies a batch of proofs targeting the same circuit, the whole batch is rejected if any
+/// proof is invalid.
+fn process_verify_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    groth16_proofs: Vec<SP1Groth16Proof>,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let registry_account = next_account_info(accounts_iter)?;
+
+    let first = groth16_proofs
+        .first()
+        .ok_or(VerificationError::InvalidInstructionData)?;
+    if groth16_proofs.iter().any(|groth16_proof| {
+        groth16_proof.operation != first.operation || groth16_proof.circuit_id != first.circuit_id
+    }) {
+        return Err(VerificationError::BatchCircuitMismatch.into());
+    }
+
+    let registry = load_vk_registry(program_id, registry_account)?;
+    let circuit = registry
+        .circuit(first.operation, first.circuit_id)
+        .ok_or(VerificationError::UnknownCircuit)?;
+
+    let proofs: Vec<(&[u8], &[u8])> = groth16_proofs
+        .iter()
+        .map(|groth16_proof| {
+            (
+                groth16_proof.proof.as_slice(),
+                groth16_proof.sp1_public_inputs.as_slice(),
+            )
+        })
+        .collect();
+    verify_proof_batch(&proofs, &circuit.sp1_vkey_hash, &circuit.groth16_vk)?;
+    Ok(())
+}
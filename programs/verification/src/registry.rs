@@ -0,0 +1,296 @@
+//! Verification key registry.
+//!
+//! The registry account stores the verification keys of every circuit version the verifier
+//! accepts, so a circuit change only needs a registry update instead of a verifier redeploy.
+//! Several versions can be registered at once and old and new clients coexist during an
+//! upgrade window. Each operation has its own circuits, so withdraw can expose the amount and
+//! recipient publicly while transfer keeps them private.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::{Pubkey, PUBKEY_BYTES},
+    rent::Rent,
+    sysvar::Sysvar,
+};
+use solana_sdk_ids::bpf_loader_upgradeable;
+use solana_system_interface::instruction as system_instruction;
+
+use crate::utils::VerificationError;
+
+/// Seed of the verification key registry account.
+pub const VK_REGISTRY_SEED: &[u8] = b"vk_registry";
+
+/// Operations a circuit proves.
+pub const OPERATION_DEPOSIT: u8 = 0;
+pub const OPERATION_TRANSFER: u8 = 1;
+pub const OPERATION_WITHDRAW: u8 = 2;
+
+/// The verification keys of one circuit version of an operation.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CircuitVk {
+    /// The operation the circuit proves, circuit ids are versioned per operation.
+    pub operation: u8,
+    pub circuit_id: u32,
+    /// The SP1 program vkey hash, derived from `vk.bytes32()` on the program's vkey.
+    pub sp1_vkey_hash: [u8; 32],
+    /// The Groth16 verification key of the SP1 version the circuit is proven with.
+    pub groth16_vk: Vec<u8>,
+}
+
+/// The registry of accepted circuits, managed by the admin.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct VkRegistry {
+    pub admin: Pubkey,
+    pub circuits: Vec<CircuitVk>,
+}
+
+impl VkRegistry {
+    /// Returns the verification keys of the given circuit of an operation.
+    pub fn circuit(&self, operation: u8, circuit_id: u32) -> Option<&CircuitVk> {
+        self.circuits
+            .iter()
+            .find(|circuit| circuit.operation == operation && circuit.circuit_id == circuit_id)
+    }
+
+    /// Registers a circuit, replacing the keys if the circuit id is already registered for
+    /// the operation.
+    pub fn set_circuit(&mut self, circuit: CircuitVk) {
+        match self.circuits.iter_mut().find(|registered| {
+            registered.operation == circuit.operation && registered.circuit_id == circuit.circuit_id
+        }) {
+            Some(registered) => *registered = circuit,
+            None => self.circuits.push(circuit),
+        }
+    }
+
+    /// Removes a circuit of an operation, returns false if it is not registered.
+    pub fn remove_circuit(&mut self, operation: u8, circuit_id: u32) -> bool {
+        let len = self.circuits.len();
+        self.circuits
+            .retain(|circuit| circuit.operation != operation || circuit.circuit_id != circuit_id);
+        self.circuits.len() != len
+    }
+}
+
+/// Derives the verification key registry account address.
+pub fn derive_vk_registry_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VK_REGISTRY_SEED], program_id)
+}
+
+/// Reads the registry account after checking its address and owner.
+pub fn load_vk_registry(
+    program_id: &Pubkey,
+    registry_account: &AccountInfo,
+) -> Result<VkRegistry, ProgramError> {
+    let (registry_pda, _bump_seed) = derive_vk_registry_pda(program_id);
+    if registry_account.key != &registry_pda || registry_account.owner != program_id {
+        return Err(VerificationError::InvalidRegistryAccount.into());
+    }
+
+    VkRegistry::deserialize(&mut &registry_account.data.borrow()[..])
+        .map_err(|_| VerificationError::BorshDeserializeError.into())
+}
+
+/// Writes the registry, resizing the account to the serialized size. The payer tops up the
+/// rent when the registry grows.
+fn store_vk_registry<'a>(
+    registry_account: &AccountInfo<'a>,
+    payer_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    registry: &VkRegistry,
+) -> ProgramResult {
+    let data = borsh::to_vec(registry).map_err(|_| VerificationError::BorshSerializeError)?;
+
+    let rent = Rent::get()?;
+    let lamports = rent
+        .minimum_balance(data.len())
+        .saturating_sub(registry_account.lamports());
+    if lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer_account.key, registry_account.key, lamports),
+            &[
+                payer_account.clone(),
+                registry_account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+    }
+
+    registry_account.realloc(data.len(), false)?;
+    registry_account.data.borrow_mut().copy_from_slice(&data);
+
+    Ok(())
+}
+
+/// Ensures the authority is the upgrade authority of the program.
+fn check_upgrade_authority(
+    program_id: &Pubkey,
+    program_data_account: &AccountInfo,
+    authority: &AccountInfo,
+) -> ProgramResult {
+    let (program_data_address, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if program_data_account.key != &program_data_address
+        || program_data_account.owner != &bpf_loader_upgradeable::id()
+    {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // UpgradeableLoaderState::ProgramData layout:
+    // u32 variant ( 3 ) | u64 slot | option tag | upgrade authority
+    let data = program_data_account.data.borrow();
+    if data.len() < 13 + PUBKEY_BYTES
+        || data[..4] != 3u32.to_le_bytes()
+        || data[12] != 1
+        || &data[13..13 + PUBKEY_BYTES] != authority.key.as_ref()
+    {
+        return Err(VerificationError::Unauthorized.into());
+    }
+
+    Ok(())
+}
+
+/// Loads the registry after checking the admin signed.
+fn load_vk_registry_as_admin(
+    program_id: &Pubkey,
+    admin_account: &AccountInfo,
+    registry_account: &AccountInfo,
+) -> Result<VkRegistry, ProgramError> {
+    if !admin_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let registry = load_vk_registry(program_id, registry_account)?;
+    if &registry.admin != admin_account.key {
+        return Err(VerificationError::Unauthorized.into());
+    }
+
+    Ok(registry)
+}
+
+/// Creates the empty registry, only the upgrade authority can initialize it and becomes the
+/// admin.
+///
+/// Accounts: payer ( upgrade authority ), registry, system program, program data.
+pub fn initialize_vk_registry(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let payer_account = next_account_info(accounts_iter)?;
+    let registry_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let program_data_account = next_account_info(accounts_iter)?;
+
+    check_upgrade_authority(program_id, program_data_account, payer_account)?;
+
+    let (registry_pda, bump_seed) = derive_vk_registry_pda(program_id);
+    if registry_account.key != &registry_pda {
+        return Err(VerificationError::InvalidRegistryAccount.into());
+    }
+
+    if registry_account.owner == program_id {
+        return Err(VerificationError::RegistryAlreadyInitialized.into());
+    }
+
+    let registry = VkRegistry {
+        admin: *payer_account.key,
+        circuits: Vec::new(),
+    };
+    let data = borsh::to_vec(&registry).map_err(|_| VerificationError::BorshSerializeError)?;
+
+    let rent = Rent::get()?;
+    invoke_signed(
+        &system_instruction::create_account(
+            payer_account.key,
+            &registry_pda,
+            rent.minimum_balance(data.len()),
+            data.len() as u64,
+            program_id,
+        ),
+        &[
+            payer_account.clone(),
+            registry_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[VK_REGISTRY_SEED, &[bump_seed]]],
+    )?;
+    registry_account.data.borrow_mut().copy_from_slice(&data);
+
+    msg!("verification key registry initialized, admin: {}", payer_account.key);
+
+    Ok(())
+}
+
+/// Registers or replaces the verification keys of a circuit.
+///
+/// Accounts: admin, registry, system program.
+pub fn set_circuit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    circuit: CircuitVk,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let registry_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if circuit.operation > OPERATION_WITHDRAW {
+        return Err(VerificationError::InvalidOperation.into());
+    }
+
+    let mut registry = load_vk_registry_as_admin(program_id, admin_account, registry_account)?;
+    msg!("set circuit: {} of operation {}", circuit.circuit_id, circuit.operation);
+    registry.set_circuit(circuit);
+
+    store_vk_registry(registry_account, admin_account, system_program, &registry)
+}
+
+/// Removes a circuit so proofs targeting it are rejected.
+///
+/// Accounts: admin, registry, system program.
+pub fn remove_circuit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    operation: u8,
+    circuit_id: u32,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let registry_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut registry = load_vk_registry_as_admin(program_id, admin_account, registry_account)?;
+    if !registry.remove_circuit(operation, circuit_id) {
+        return Err(VerificationError::UnknownCircuit.into());
+    }
+    msg!("removed circuit: {} of operation {}", circuit_id, operation);
+
+    store_vk_registry(registry_account, admin_account, system_program, &registry)
+}
+
+/// Hands the registry admin role over to a new admin.
+///
+/// Accounts: admin, registry, system program.
+pub fn set_admin(program_id: &Pubkey, accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let admin_account = next_account_info(accounts_iter)?;
+    let registry_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut registry = load_vk_registry_as_admin(program_id, admin_account, registry_account)?;
+    registry.admin = new_admin;
+    msg!("registry admin set to: {}", new_admin);
+
+    store_vk_registry(registry_account, admin_account, system_program, &registry)
+}
@@ -8,7 +8,11 @@ use ark_bn254::{Fq, G1Affine};
 use ark_ff::PrimeField;
 use ark_serialize::CanonicalSerialize;
 use sha2::{Digest, Sha256};
-use solana_program::{decode_error::DecodeError, program_error::ProgramError};
+// DecodeError is deprecated in solana-program 2.2, kept as clients decode
+// the custom errors with it
+#[allow(deprecated)]
+use solana_program::decode_error::DecodeError;
+use solana_program::program_error::ProgramError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -43,6 +47,18 @@ pub enum VerificationError {
     Groth16VkeyHashMismatch,
     #[error("Invalid program vkey hash")]
     InvalidProgramVkeyHash,
+    #[error("Invalid verification key registry account")]
+    InvalidRegistryAccount,
+    #[error("Verification key registry already initialized")]
+    RegistryAlreadyInitialized,
+    #[error("Signer is not the registry admin or upgrade authority")]
+    Unauthorized,
+    #[error("Circuit is not registered")]
+    UnknownCircuit,
+    #[error("Invalid circuit operation")]
+    InvalidOperation,
+    #[error("Batch proofs target different circuits")]
+    BatchCircuitMismatch,
 }
 
 impl From<VerificationError> for ProgramError {
@@ -50,6 +66,7 @@ impl From<VerificationError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+#[allow(deprecated)]
 impl<T> DecodeError<T> for VerificationError {
     fn type_of() -> &'static str {
         "VerificationError"
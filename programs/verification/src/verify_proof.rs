@@ -24,6 +24,7 @@
 
 use groth16_solana::groth16::Groth16Verifyingkey;
 use sha2::{Digest, Sha256};
+use solana_bn254::prelude::{alt_bn128_addition, alt_bn128_multiplication, alt_bn128_pairing};
 use crate::utils::*;
 
 /// Groth16 verification keys for different SP1 versions.
@@ -80,6 +81,20 @@ pub fn verify_proof(
     sp1_public_inputs: &[u8],
     sp1_vkey_hash: &str,
     groth16_vk: &[u8],
+) -> Result<(), VerificationError> {
+    let sp1_vkey_hash = decode_sp1_vkey_hash(sp1_vkey_hash)?;
+
+    verify_proof_with_hash(proof, sp1_public_inputs, &sp1_vkey_hash, groth16_vk)
+}
+
+/// Verifies a proof generated by [`SP1ProofWithPublicValues`] with an already decoded vkey hash,
+/// as stored in the verification key registry.
+#[inline]
+pub fn verify_proof_with_hash(
+    proof: &[u8],
+    sp1_public_inputs: &[u8],
+    sp1_vkey_hash: &[u8; 32],
+    groth16_vk: &[u8],
 ) -> Result<(), VerificationError> {
     // Hash the vk and get the first 4 bytes.
     let groth16_vk_hash: [u8; 4] = Sha256::digest(groth16_vk)[..4].try_into().unwrap();
@@ -93,19 +108,154 @@ pub fn verify_proof(
         return Err(VerificationError::Groth16VkeyHashMismatch);
     }
 
-    let sp1_vkey_hash = decode_sp1_vkey_hash(sp1_vkey_hash)?;
-
     // Verify the proof.
     verify_proof_raw(
         &proof[4..],
-        &groth16_public_values(&sp1_vkey_hash, sp1_public_inputs),
+        &groth16_public_values(sp1_vkey_hash, sp1_public_inputs),
         groth16_vk,
     )
 }
 
+/// Number of bytes of the random batch coefficients. 128 bit coefficients keep the probability
+/// that a batch containing an invalid proof passes negligible.
+const BATCH_COEFFICIENT_LEN: usize = 16;
+
+fn g1_add(a: &[u8; 64], b: &[u8; 64]) -> Result<[u8; 64], VerificationError> {
+    let result = alt_bn128_addition(&[a.as_slice(), b.as_slice()].concat())
+        .map_err(|_| VerificationError::ArithmeticError)?;
+    result.try_into().map_err(|_| VerificationError::ArithmeticError)
+}
+
+fn g1_mul(point: &[u8; 64], scalar: &[u8; 32]) -> Result<[u8; 64], VerificationError> {
+    let result = alt_bn128_multiplication(&[point.as_slice(), scalar.as_slice()].concat())
+        .map_err(|_| VerificationError::ArithmeticError)?;
+    result.try_into().map_err(|_| VerificationError::ArithmeticError)
+}
+
+/// Adds two big endian scalars. The batch coefficients are 128 bit so their sum never
+/// overflows 256 bits.
+fn add_scalars(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut sum = [0u8; 32];
+    let mut carry = 0u16;
+    for i in (0..32).rev() {
+        let value = a[i] as u16 + b[i] as u16 + carry;
+        sum[i] = value as u8;
+        carry = value >> 8;
+    }
+    sum
+}
+
+/// Derives the batch coefficients from all the proofs and public inputs of the batch, so the
+/// prover cannot choose proofs whose errors cancel out in the random linear combination.
+fn batch_coefficients(proofs: &[(&[u8], &[u8])]) -> Vec<[u8; 32]> {
+    let mut transcript = Sha256::new();
+    for (proof, sp1_public_inputs) in proofs {
+        transcript.update((proof.len() as u64).to_le_bytes());
+        transcript.update(proof);
+        transcript.update((sp1_public_inputs.len() as u64).to_le_bytes());
+        transcript.update(sp1_public_inputs);
+    }
+    let seed = transcript.finalize();
+
+    (0..proofs.len() as u64)
+        .map(|i| {
+            let hash = Sha256::new()
+                .chain_update(seed)
+                .chain_update(i.to_le_bytes())
+                .finalize();
+            let mut coefficient = [0u8; 32];
+            coefficient[32 - BATCH_COEFFICIENT_LEN..].copy_from_slice(&hash[..BATCH_COEFFICIENT_LEN]);
+            coefficient
+        })
+        .collect()
+}
+
+/// Verifies a batch of proofs generated by [`SP1ProofWithPublicValues`] for the same circuit.
+///
+/// Instead of checking `e(-A, B) e(alpha, beta) e(L, gamma) e(C, delta) = 1` for every proof,
+/// the checks are combined with random coefficients `r`:
+///
+/// `prod(e(-r A, B)) e(sum(r) alpha, beta) e(sum(r L), gamma) e(sum(r C), delta) = 1`
+///
+/// so a batch of N proofs costs N + 3 pairings instead of 4N. The whole batch is rejected if any
+/// proof is invalid.
+pub fn verify_proof_batch(
+    proofs: &[(&[u8], &[u8])],
+    sp1_vkey_hash: &[u8; 32],
+    groth16_vk: &[u8],
+) -> Result<(), VerificationError> {
+    if proofs.is_empty() {
+        return Err(VerificationError::InvalidInput);
+    }
+
+    // Hash the vk and get the first 4 bytes.
+    let groth16_vk_hash: [u8; 4] = Sha256::digest(groth16_vk)[..4].try_into().unwrap();
+    let vk = load_groth16_verifying_key_from_bytes(groth16_vk)?;
+    let coefficients = batch_coefficients(proofs);
+
+    let mut pairing_input: Vec<u8> = Vec::with_capacity((proofs.len() + 3) * 192);
+    let mut coefficient_sum = [0u8; 32];
+    let mut prepared_inputs_sum: Option<[u8; 64]> = None;
+    let mut pi_c_sum: Option<[u8; 64]> = None;
+
+    for ((proof, sp1_public_inputs), coefficient) in proofs.iter().zip(coefficients.iter()) {
+        if proof.len() < 4 || groth16_vk_hash != proof[..4] {
+            return Err(VerificationError::Groth16VkeyHashMismatch);
+        }
+
+        let proof = load_proof_from_bytes(&proof[4..])?;
+        let public_inputs = load_public_inputs_from_bytes(&groth16_public_values(
+            sp1_vkey_hash,
+            sp1_public_inputs,
+        ))?;
+        if vk.vk_ic.len() != public_inputs.inputs.len() + 1 {
+            return Err(VerificationError::InvalidPublicInput);
+        }
+
+        // L = IC[0] + sum(IC[i + 1] * input[i])
+        let mut prepared_inputs = vk.vk_ic[0];
+        for (input, ic) in public_inputs.inputs.iter().zip(vk.vk_ic[1..].iter()) {
+            prepared_inputs = g1_add(&prepared_inputs, &g1_mul(ic, input)?)?;
+        }
+
+        // pi_a is already negated when loading the proof
+        pairing_input.extend_from_slice(&g1_mul(&proof.pi_a, coefficient)?);
+        pairing_input.extend_from_slice(&proof.pi_b);
+
+        let prepared_inputs = g1_mul(&prepared_inputs, coefficient)?;
+        prepared_inputs_sum = Some(match prepared_inputs_sum {
+            Some(sum) => g1_add(&sum, &prepared_inputs)?,
+            None => prepared_inputs,
+        });
+        let pi_c = g1_mul(&proof.pi_c, coefficient)?;
+        pi_c_sum = Some(match pi_c_sum {
+            Some(sum) => g1_add(&sum, &pi_c)?,
+            None => pi_c,
+        });
+        coefficient_sum = add_scalars(&coefficient_sum, coefficient);
+    }
+
+    pairing_input.extend_from_slice(&g1_mul(&vk.vk_alpha_g1, &coefficient_sum)?);
+    pairing_input.extend_from_slice(&vk.vk_beta_g2);
+    pairing_input.extend_from_slice(&prepared_inputs_sum.ok_or(VerificationError::InvalidInput)?);
+    pairing_input.extend_from_slice(&vk.vk_gamma_g2);
+    pairing_input.extend_from_slice(&pi_c_sum.ok_or(VerificationError::InvalidInput)?);
+    pairing_input.extend_from_slice(&vk.vk_delta_g2);
+
+    let pairing_result =
+        alt_bn128_pairing(&pairing_input).map_err(|_| VerificationError::PairingError)?;
+    if pairing_result.len() != 32 || pairing_result[31] != 1 || pairing_result[..31] != [0u8; 31] {
+        return Err(VerificationError::VerificationError);
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test{
-    use crate::{verify_proof, METHOD_VKEY_HASH};
+    use super::{verify_proof, verify_proof_batch};
+    use crate::utils::{decode_sp1_vkey_hash, VerificationError};
+    use crate::METHOD_VKEY_HASH;
     use sp1_sdk::SP1ProofWithPublicValues;
 
     #[test]
@@ -129,4 +279,126 @@ mod test{
             vk,
         ).unwrap();
     }
+
+    /// Loads a proof of the methods program as its proof bytes and public inputs.
+    fn load_proof(path: &str) -> (Vec<u8>, Vec<u8>) {
+        let sp1_proof_with_public_values = SP1ProofWithPublicValues::load(path).unwrap();
+        (
+            sp1_proof_with_public_values.bytes(),
+            sp1_proof_with_public_values.public_values.to_vec(),
+        )
+    }
+
+    /// Loads the distinct deposit, transfer and withdraw proofs of the methods program.
+    fn load_proofs() -> Vec<(Vec<u8>, Vec<u8>)> {
+        [
+            "../../tests/verification-test/bin/methods_proof.bin",
+            "../../tests/verification-test/bin/methods_transfer_proof.bin",
+            "../../tests/verification-test/bin/methods_withdraw_proof.bin",
+        ]
+        .iter()
+        .map(|path| load_proof(path))
+        .collect()
+    }
+
+    #[test]
+    fn test_verify_batch_from_sp1() {
+        let proofs = load_proofs();
+        let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+        let vkey_hash = decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap();
+
+        let valid = (proofs[0].0.as_slice(), proofs[0].1.as_slice());
+        verify_proof_batch(&[valid], &vkey_hash, vk).unwrap();
+        verify_proof_batch(&[valid, valid], &vkey_hash, vk).unwrap();
+
+        // one invalid proof rejects the whole batch
+        let mut tampered_inputs = proofs[0].1.clone();
+        tampered_inputs[0] ^= 1;
+        let invalid = (proofs[0].0.as_slice(), tampered_inputs.as_slice());
+        assert!(verify_proof_batch(&[valid, invalid], &vkey_hash, vk).is_err());
+    }
+
+    #[test]
+    fn test_verify_batch_distinct_proofs() {
+        let proofs = load_proofs();
+        let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+        let vkey_hash = decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap();
+
+        let batch: Vec<(&[u8], &[u8])> = proofs
+            .iter()
+            .map(|(proof, public_inputs)| (proof.as_slice(), public_inputs.as_slice()))
+            .collect();
+        for (proof, public_inputs) in batch.iter() {
+            verify_proof(proof, public_inputs, &METHOD_VKEY_HASH, vk).unwrap();
+        }
+        verify_proof_batch(&batch, &vkey_hash, vk).unwrap();
+
+        // the order of the proofs does not matter
+        let reversed: Vec<(&[u8], &[u8])> = batch.iter().rev().copied().collect();
+        verify_proof_batch(&reversed, &vkey_hash, vk).unwrap();
+    }
+
+    #[test]
+    fn test_verify_batch_tampered_proof() {
+        let proofs = load_proofs();
+        let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+        let vkey_hash = decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap();
+
+        // the deposit proof with the public inputs of the transfer proof is well formed but
+        // proves a different statement
+        let tampered = (proofs[0].0.as_slice(), proofs[1].1.as_slice());
+        let valid: Vec<(&[u8], &[u8])> = proofs
+            .iter()
+            .map(|(proof, public_inputs)| (proof.as_slice(), public_inputs.as_slice()))
+            .collect();
+
+        for position in 0..=valid.len() {
+            let mut batch = valid.clone();
+            batch.insert(position, tampered);
+            assert!(matches!(
+                verify_proof_batch(&batch, &vkey_hash, vk),
+                Err(VerificationError::VerificationError)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verify_batch_empty() {
+        let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+        let vkey_hash = decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap();
+
+        assert!(matches!(
+            verify_proof_batch(&[], &vkey_hash, vk),
+            Err(VerificationError::InvalidInput)
+        ));
+    }
+
+    #[test]
+    fn test_verify_batch_mixed_circuits() {
+        let proofs = load_proofs();
+        let vk = crate::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+        let vkey_hash = decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap();
+        let valid: Vec<(&[u8], &[u8])> = proofs
+            .iter()
+            .map(|(proof, public_inputs)| (proof.as_slice(), public_inputs.as_slice()))
+            .collect();
+
+        // a proof for another groth16 circuit carries another vk hash prefix
+        let mut other_vk_proof = proofs[1].0.clone();
+        other_vk_proof[0] ^= 1;
+        let mut batch = valid.clone();
+        batch[1] = (other_vk_proof.as_slice(), proofs[1].1.as_slice());
+        assert!(matches!(
+            verify_proof_batch(&batch, &vkey_hash, vk),
+            Err(VerificationError::Groth16VkeyHashMismatch)
+        ));
+
+        // proofs of the methods program do not verify for another SP1 program
+        let mut other_vkey_hash = vkey_hash;
+        other_vkey_hash[31] ^= 1;
+        assert!(matches!(
+            verify_proof_batch(&valid, &other_vkey_hash, vk),
+            Err(VerificationError::VerificationError)
+        ));
+    }
 }
@@ -31,4 +31,7 @@ echo "Verification program ID: $VERIFICATION_ID"
 
 # Run test
 cargo test --package verification-test --release -- process::test_process_instruction_transfer
+# check the CPI and in-process verifier give the same results
+cargo test --package verification-test --release -- verifier_modes
+cargo test --package verification-test --release --no-default-features -- verifier_modes
 # cargo test --package verification-test --release -- process::test_process_instruction_withdraw
\ No newline at end of file
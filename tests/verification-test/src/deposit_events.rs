@@ -0,0 +1,53 @@
+use darksol::merkle::{hash_precommits, CommitmentsAccount};
+use darksol::processor::insert_deposit;
+use darksol::{DepositRequest, PreCommitments, ShieldCipherText};
+use rand::Rng;
+use veil_types::MerkleTreeSparse;
+
+const TREE_DEPTH: usize = 4;
+const ROUNDS: usize = 20;
+
+// replay_deposits deposits random notes into trees of TREE_DEPTH, rolling
+// over to a new tree when the current one is full like the deposit
+// instruction, and checks every event against an off-chain replay
+fn replay_deposits(rng: &mut impl Rng, deposits: usize) {
+    let mut tree_number = 1;
+    let mut tree = CommitmentsAccount::new(tree_number, TREE_DEPTH, 4);
+    let mut replay = MerkleTreeSparse::<TREE_DEPTH>::new(tree_number);
+
+    for _ in 0..deposits {
+        if tree.exceed_tree_depth(1) {
+            tree_number += 1;
+            tree = CommitmentsAccount::new(tree_number, TREE_DEPTH, 4);
+            replay = MerkleTreeSparse::<TREE_DEPTH>::new(tree_number);
+        }
+
+        let pre_commitments = PreCommitments::new(
+            rng.random(),
+            rng.random::<[u8; 32]>().to_vec(),
+            rng.random::<[u8; 32]>().to_vec(),
+        );
+        let request = DepositRequest::new(
+            pre_commitments.clone(),
+            ShieldCipherText::new(vec![], vec![], vec![]),
+        );
+        let event = insert_deposit(&mut tree, &request, 0).unwrap();
+
+        let leaf = hash_precommits(pre_commitments);
+        replay.insert(vec![leaf.clone()]);
+
+        assert_eq!(event.tree_number, tree_number);
+        assert_eq!(event.start_position, replay.generate_proof(leaf).index as u64);
+        assert_eq!(event.merkle_root, replay.root());
+    }
+}
+
+#[test]
+fn test_deposit_events_match_replay() {
+    let mut rng = rand::rng();
+    for _ in 0..ROUNDS {
+        // up to three trees of 16 leaves
+        let deposits = rng.random_range(1..=3 << TREE_DEPTH);
+        replay_deposits(&mut rng, deposits);
+    }
+}
@@ -12,6 +12,10 @@ use borsh::{BorshDeserialize, BorshSerialize};
 
 pub mod process;
 pub mod util;
+#[cfg(test)]
+mod deposit_events;
+#[cfg(test)]
+mod verifier_modes;
 /// The ELF (executable and linkable format) file for the Succinct RISC-V zkVM.
 pub const METHODS_ELF: &[u8] =  include_bytes!("../bin/methods");
 
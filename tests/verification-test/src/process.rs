@@ -1,8 +1,9 @@
 use borsh::{de, BorshDeserialize};
-use darksol::{derive_pda, PreCommitments, SP1Groth16Proof, TransferRequest, WithdrawRequest};
+use darksol::{derive_native_vault_pda, derive_pda, derive_vault_pda, BoundParams, PreCommitments, OPERATION_TRANSFER, OPERATION_WITHDRAW, NATIVE_SOL_TOKEN_ID, SP1Groth16Proof, TransferRequest, WithdrawRequest, TREE_DEPTH};
 use darksol::merkle::{hash_precommits, CommitmentsAccount};
-use darksol::utils::account::get_associated_token_address_and_bump_seed;
-use darksol::utils::serialize::BorshDeserializeWithLength;
+use darksol::config::{derive_config_pda, derive_fee_config_pda};
+use darksol::nullifier::derive_nullifier_pda;
+use verification::registry::derive_vk_registry_pda;
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::compute_budget::ComputeBudgetInstruction;
 use solana_sdk::instruction::{AccountMeta, Instruction};
@@ -10,7 +11,8 @@ use solana_sdk::transaction::Transaction;
 use solana_sdk::message::Message;
 use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signer::Signer, system_instruction};
 use solana_sdk::pubkey;
-use solana_program::system_program::ID as SYSTEM_PROGRAM_ID;
+use solana_sdk_ids::bpf_loader_upgradeable;
+use solana_sdk_ids::system_program::ID as SYSTEM_PROGRAM_ID;
 use spl_associated_token_account::get_associated_token_address;
 use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
 use spl_token::instruction::sync_native;
@@ -18,7 +20,7 @@ use veil_types::{generate_nullifier, sha256, MerkleTreeSparse, UTXO};
 use serde::{Serialize, Deserialize};
 use sp1_sdk::install::try_install_circuit_artifacts;
 
-use crate::util::{create_ata, create_deposit_instructions_data_test, generate_proof_transfer, generate_proof_withdraw, generate_random_bytes};
+use crate::util::{create_ata, create_deposit_instructions_data_test, generate_proof_transfer, generate_proof_withdraw, generate_random_bytes, load_upgrade_authority, register_circuit, CIRCUIT_ID};
 
 #[derive(Serialize, Deserialize)]
 pub struct TransferInput {
@@ -106,8 +108,10 @@ async fn test_process_instruction_transfer() {
 
     // initialize
 
+    // only the upgrade authority ( the deployer ) can initialize the program
+    let upgrade_authority = load_upgrade_authority();
     let mut account_metas: Vec<AccountMeta> = vec![];
-    account_metas.push(AccountMeta::new(depositor_pubkey, true));
+    account_metas.push(AccountMeta::new(upgrade_authority.pubkey(), true));
     let (funding_pda, _bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], &program_id);
     account_metas.push(AccountMeta::new(funding_pda, false));
     let (commitments_pda, _bump_seed) = derive_pda(1, &program_id);
@@ -116,6 +120,13 @@ async fn test_process_instruction_transfer() {
         Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
     account_metas.push(AccountMeta::new(SYSTEM_PROGRAM_ID, false));
+    let (config_pda, _bump_seed) = derive_config_pda(&program_id);
+    account_metas.push(AccountMeta::new(config_pda, false));
+    let (program_data, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    account_metas.push(AccountMeta::new_readonly(program_data, false));
+    // proofs are only verified with the verification program pinned at initialize
+    account_metas.push(AccountMeta::new_readonly(verification_program_id, false));
 
     let instruction = Instruction {
         program_id,
@@ -124,11 +135,15 @@ async fn test_process_instruction_transfer() {
     };
 
     let mut transaction =
-        Transaction::new_with_payer(&[instruction], Some(&depositor_keypair.pubkey()));
+        Transaction::new_with_payer(&[instruction], Some(&upgrade_authority.pubkey()));
 
-    transaction.sign(&[&depositor_keypair], rpc_client.get_latest_blockhash().await.unwrap());
+    transaction.sign(&[&upgrade_authority], rpc_client.get_latest_blockhash().await.unwrap());
     rpc_client.send_and_confirm_transaction(&transaction).await.unwrap();
 
+    // register the circuit the proofs are generated with
+    register_circuit(&rpc_client, &verification_program_id).await;
+    let (vk_registry_pda, _bump_seed) = derive_vk_registry_pda(&verification_program_id);
+
     let ata = get_associated_token_address(&depositor_pubkey, &spl_token::native_mint::ID);
     let amount = 1 * 10_u64.pow(9); /* Wrapped SOL's decimals is 9, hence amount to wrap is 1 SOL */
 
@@ -171,7 +186,7 @@ async fn test_process_instruction_transfer() {
     // funding_account
     // user_wallet
     // user_token_account
-    // pda_token_account
+    // vault_account
     // mint_account
     // commitments_account
     // commitments_manager_account
@@ -183,31 +198,26 @@ async fn test_process_instruction_transfer() {
     account_metas.push(AccountMeta::new(funding_pda, false));
     account_metas.push(AccountMeta::new(depositor_pubkey, true));
     account_metas.push(AccountMeta::new(ata, false));
-    let (funding_ata, ata_bump) = get_associated_token_address_and_bump_seed(
-        &funding_pda,
-        &spl_token::native_mint::ID,
-        &spl_associated_token_account::ID,
-        &spl_token::ID,
-    );
-    let (funding_ata, ata_bump) = Pubkey::find_program_address(&[b"funding_ata"], &program_id);
-    println!("bump {:?}", ata_bump);
-    account_metas.push(AccountMeta::new(funding_ata, false));
+    let (vault_pda, _vault_bump) =
+        derive_vault_pda(&spl_token::native_mint::ID, &spl_token::ID, &program_id);
+    account_metas.push(AccountMeta::new(vault_pda, false));
     account_metas.push(AccountMeta::new_readonly(spl_token::native_mint::ID, false));
     let (commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
     account_metas.push(AccountMeta::new(commitments_pda, false));
     let (commitments_manager_pda, _bump_seed) =
         Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new_readonly(config_pda, false));
+    // no fee is configured for the token, the treasury is not used
+    let (fee_config_pda, _bump_seed) = derive_fee_config_pda(&spl_token::native_mint::ID.to_bytes(), &program_id);
+    account_metas.push(AccountMeta::new_readonly(fee_config_pda, false));
+    account_metas.push(AccountMeta::new(depositor_pubkey, false));
     account_metas.push(AccountMeta::new_readonly(spl_token::ID, false));
     account_metas.push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
     account_metas.push(AccountMeta::new_readonly(
         solana_program::rent::sysvar::ID,
         false,
     ));
-    account_metas.push(AccountMeta::new_readonly(
-        spl_associated_token_account::ID,
-        false,
-    ));
 
     for i in account_metas.iter() {
         println!("Account: {}", i.pubkey);
@@ -314,12 +324,12 @@ async fn test_process_instruction_transfer() {
         Err(err) => panic!("Deposit transaction failed: {:?}", err),
     };
 
-    let commitments_account: CommitmentsAccount<15>;
-    match rpc_client.get_account(&commitments_pda).await {
+    let commitments_root = match rpc_client.get_account(&commitments_pda).await {
         Ok(account  ) => {
             let account_data = account.data;
-            commitments_account = CommitmentsAccount::try_from_slice_with_length(&account_data).unwrap();
-            assert!(commitments_account.next_leaf_index == 3);
+            let commitments_account = CommitmentsAccount::load(&account_data[..]).unwrap();
+            assert!(commitments_account.next_leaf_index() == 3);
+            commitments_account.root()
         },
         Err(e) => panic!("Failed to get account data: {}", e),
     };
@@ -335,7 +345,7 @@ async fn test_process_instruction_transfer() {
     let inserted_leaf_3 = hash_precommits(pre_commitment);
 
     tree.insert(vec![inserted_leaf.clone(), inserted_leaf_2.clone(), inserted_leaf_3.clone()]);
-    assert_eq!(tree.root(), commitments_account.root());
+    assert_eq!(tree.root(), commitments_root);
 
     // generate proof
     use std::time::Instant;
@@ -359,6 +369,18 @@ async fn test_process_instruction_transfer() {
     let receiver_nullying_key_4 = sha256(vec![receiver_view_key_4.secret().to_bytes().as_ref()]);
     let receiver_master_pubkey_4 = sha256(vec![receiver_spend_key_4.pubkey().to_bytes().as_ref(), receiver_nullying_key_4.as_slice()]);
 
+    // instruction context the proof is bound to
+    let params_hash = BoundParams {
+        program_id: program_id.to_bytes().to_vec(),
+        tree_numbers: vec![tree_number; 3],
+        operation: OPERATION_TRANSFER,
+        recipient: vec![],
+        fee: 0,
+        relayer: None,
+    }
+    .hash()
+    .unwrap();
+
     let (proof, nullifiers, ciphertext, utxo_hashes) = generate_proof_transfer(
         tree.clone(),
         vec![inserted_leaf.clone(), inserted_leaf_2.clone(), inserted_leaf_3.clone()],
@@ -380,17 +402,19 @@ async fn test_process_instruction_transfer() {
         ],
         &spl_token::native_mint::ID,
         &depositor_view_key,
+        params_hash,
     );
     println!("Time taken to generate proof: {:?}", now.elapsed());
 
     let mut transfer_request = TransferRequest::new(
         proof.bytes().to_vec(),
-        tree.root(),
-        tree_number,
+        TREE_DEPTH as u64,
+        CIRCUIT_ID,
         ciphertext,
     );
+    // all inputs are in the same tree
     nullifiers.iter().for_each(|nullifier| {
-        transfer_request.push_nullifiers(nullifier.clone());
+        transfer_request.push_nullifiers(nullifier.clone(), tree_number, tree.root());
     });
     utxo_hashes.iter().for_each(|utxo_hash| {
         transfer_request.push_encrypted_commitments(utxo_hash.clone());
@@ -406,22 +430,41 @@ async fn test_process_instruction_transfer() {
 
     // get all necessary account meta
     // user wallet
-    // spent commitments account
     // current commitments account
     // commitments manager account
+    // verification program
+    // verification key registry
+    // funding account
+    // system program
+    // spent commitments accounts
+    // nullifier accounts
 
     let mut account_metas: Vec<AccountMeta> = vec![];
     account_metas.push(AccountMeta::new(depositor_pubkey.clone(), true));
 
-    let (spent_commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
-    account_metas.push(AccountMeta::new(spent_commitments_pda, false));
-
     let (current_commitments_pda, _bump_seed) = derive_pda(newest_tree_number, &program_id);
     account_metas.push(AccountMeta::new(current_commitments_pda, false));
 
     let (commitments_manager_pda, _bump_seed) =
         Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new_readonly(config_pda, false));
+    account_metas.push(AccountMeta::new_readonly(verification_program_id, false));
+    account_metas.push(AccountMeta::new_readonly(vk_registry_pda, false));
+    account_metas.push(AccountMeta::new(funding_pda, false));
+    account_metas.push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+
+    // one spent commitments account per spent UTXO
+    let (spent_commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
+    nullifiers.iter().for_each(|_| {
+        account_metas.push(AccountMeta::new(spent_commitments_pda, false));
+    });
+
+    // one nullifier account per spent UTXO
+    nullifiers.iter().for_each(|nullifier| {
+        let (nullifier_pda, _bump_seed) = derive_nullifier_pda(nullifier, &program_id);
+        account_metas.push(AccountMeta::new(nullifier_pda, false));
+    });
 
     // insert variant bytes
     serialized_data.insert(0, 1);
@@ -514,8 +557,10 @@ async fn test_process_instruction_withdraw() {
 
     // initialize
 
+    // only the upgrade authority ( the deployer ) can initialize the program
+    let upgrade_authority = load_upgrade_authority();
     let mut account_metas: Vec<AccountMeta> = vec![];
-    account_metas.push(AccountMeta::new(depositor_pubkey, true));
+    account_metas.push(AccountMeta::new(upgrade_authority.pubkey(), true));
     let (funding_pda, _bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], &program_id);
     account_metas.push(AccountMeta::new(funding_pda, false));
     let (commitments_pda, _bump_seed) = derive_pda(1, &program_id);
@@ -524,6 +569,13 @@ async fn test_process_instruction_withdraw() {
         Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
     account_metas.push(AccountMeta::new(SYSTEM_PROGRAM_ID, false));
+    let (config_pda, _bump_seed) = derive_config_pda(&program_id);
+    account_metas.push(AccountMeta::new(config_pda, false));
+    let (program_data, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    account_metas.push(AccountMeta::new_readonly(program_data, false));
+    // proofs are only verified with the verification program pinned at initialize
+    account_metas.push(AccountMeta::new_readonly(verification_program_id, false));
 
     let instruction = Instruction {
         program_id,
@@ -532,11 +584,15 @@ async fn test_process_instruction_withdraw() {
     };
 
     let mut transaction =
-        Transaction::new_with_payer(&[instruction], Some(&depositor_keypair.pubkey()));
+        Transaction::new_with_payer(&[instruction], Some(&upgrade_authority.pubkey()));
 
-    transaction.sign(&[&depositor_keypair], rpc_client.get_latest_blockhash().await.unwrap());
+    transaction.sign(&[&upgrade_authority], rpc_client.get_latest_blockhash().await.unwrap());
     rpc_client.send_and_confirm_transaction(&transaction).await.unwrap();
 
+    // register the circuit the proofs are generated with
+    register_circuit(&rpc_client, &verification_program_id).await;
+    let (vk_registry_pda, _bump_seed) = derive_vk_registry_pda(&verification_program_id);
+
     let ata = get_associated_token_address(&depositor_pubkey, &spl_token::native_mint::ID);
     let amount = 1 * 10_u64.pow(9); /* Wrapped SOL's decimals is 9, hence amount to wrap is 1 SOL */
 
@@ -579,7 +635,7 @@ async fn test_process_instruction_withdraw() {
     // funding_account
     // user_wallet
     // user_token_account
-    // pda_token_account
+    // vault_account
     // mint_account
     // commitments_account
     // commitments_manager_account
@@ -591,31 +647,26 @@ async fn test_process_instruction_withdraw() {
     account_metas.push(AccountMeta::new(funding_pda, false));
     account_metas.push(AccountMeta::new(depositor_pubkey, true));
     account_metas.push(AccountMeta::new(ata, false));
-    let (funding_ata, ata_bump) = get_associated_token_address_and_bump_seed(
-        &funding_pda,
-        &spl_token::native_mint::ID,
-        &spl_associated_token_account::ID,
-        &spl_token::ID,
-    );
-    let (funding_ata, ata_bump) = Pubkey::find_program_address(&[b"funding_ata"], &program_id);
-    println!("bump {:?}", ata_bump);
-    account_metas.push(AccountMeta::new(funding_ata, false));
+    let (vault_pda, _vault_bump) =
+        derive_vault_pda(&spl_token::native_mint::ID, &spl_token::ID, &program_id);
+    account_metas.push(AccountMeta::new(vault_pda, false));
     account_metas.push(AccountMeta::new_readonly(spl_token::native_mint::ID, false));
     let (commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
     account_metas.push(AccountMeta::new(commitments_pda, false));
     let (commitments_manager_pda, _bump_seed) =
         Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new_readonly(config_pda, false));
+    // no fee is configured for the token, the treasury is not used
+    let (fee_config_pda, _bump_seed) = derive_fee_config_pda(&spl_token::native_mint::ID.to_bytes(), &program_id);
+    account_metas.push(AccountMeta::new_readonly(fee_config_pda, false));
+    account_metas.push(AccountMeta::new(depositor_pubkey, false));
     account_metas.push(AccountMeta::new_readonly(spl_token::ID, false));
     account_metas.push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
     account_metas.push(AccountMeta::new_readonly(
         solana_program::rent::sysvar::ID,
         false,
     ));
-    account_metas.push(AccountMeta::new_readonly(
-        spl_associated_token_account::ID,
-        false,
-    ));
 
     for i in account_metas.iter() {
         println!("Account: {}", i.pubkey);
@@ -642,12 +693,12 @@ async fn test_process_instruction_withdraw() {
         Err(err) => panic!("Deposit transaction failed: {:?}", err),
     };
 
-    let commitments_account: CommitmentsAccount<15>;
-    match rpc_client.get_account(&commitments_pda).await {
+    let commitments_root = match rpc_client.get_account(&commitments_pda).await {
         Ok(account  ) => {
             let account_data = account.data;
-            commitments_account = CommitmentsAccount::try_from_slice_with_length(&account_data).unwrap();
-            assert!(commitments_account.next_leaf_index == 1);
+            let commitments_account = CommitmentsAccount::load(&account_data[..]).unwrap();
+            assert!(commitments_account.next_leaf_index() == 1);
+            commitments_account.root()
         },
         Err(e) => panic!("Failed to get account data: {}", e),
     };
@@ -657,7 +708,7 @@ async fn test_process_instruction_withdraw() {
     let inserted_leaf = hash_precommits(pre_commitment);
 
     tree.insert(vec![inserted_leaf.clone()]);
-    assert_eq!(tree.root(), commitments_account.root());
+    assert_eq!(tree.root(), commitments_root);
 
     // withdraw 
 
@@ -673,6 +724,17 @@ async fn test_process_instruction_withdraw() {
     // generate proof
     use std::time::Instant;
     let now = Instant::now();
+    // instruction context the proof is bound to, no fee is configured
+    let params_hash = BoundParams {
+        program_id: program_id.to_bytes().to_vec(),
+        tree_numbers: vec![tree_number],
+        operation: OPERATION_WITHDRAW,
+        recipient: receiver_token_addr.to_bytes().to_vec(),
+        fee: 0,
+        relayer: None,
+    }
+    .hash()
+    .unwrap();
     let (proof, nullifiers, ciphertext, utxo_hashes) = generate_proof_withdraw(
         tree.clone(),
         vec![inserted_leaf.clone()],
@@ -684,6 +746,7 @@ async fn test_process_instruction_withdraw() {
         &depositor_view_key,
         &receiver_spend_key,
         &receiver_view_key,
+        params_hash,
     );
     println!("Time taken to generate proof: {:?}", now.elapsed());
     let ciphertext = ciphertext.unwrap();
@@ -702,14 +765,14 @@ async fn test_process_instruction_withdraw() {
 
     let mut withdraw_request = WithdrawRequest::new(
         proof.bytes().to_vec(),
-        tree.root(),
-        tree_number,
+        TREE_DEPTH as u64,
+        CIRCUIT_ID,
         5 * 10_u64.pow(8),
         spl_token::native_mint::ID.to_bytes().to_vec(),
         vec![ciphertext],
     );
     nullifiers.iter().for_each(|nullifier| {
-        withdraw_request.push_nullifiers(nullifier.clone());
+        withdraw_request.push_nullifiers(nullifier.clone(), tree_number, tree.root());
     });
     utxo_hashes.iter().for_each(|utxo_hash| {
         withdraw_request.push_encrypted_commitment(utxo_hash.clone());
@@ -722,11 +785,15 @@ async fn test_process_instruction_withdraw() {
 
     // get all necessary account meta
     // funding account
-    // spent commitments account
     // user wallet
     // user token account
-    // funding token account
+    // vault account
     // token program
+    // verification program
+    // verification key registry
+    // system program
+    // spent commitments accounts
+    // nullifier accounts
     //
     // current commitment account
     // commitments manager account
@@ -735,19 +802,35 @@ async fn test_process_instruction_withdraw() {
 
     let (funding_pda, _bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], &program_id);
     account_metas.push(AccountMeta::new(funding_pda, false));
-    let (spent_commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
-    account_metas.push(AccountMeta::new(spent_commitments_pda, false));
     let (commitments_manager_pda, _bump_seed) = Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
     account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new_readonly(config_pda, false));
+    // no fee is configured for the token, the treasury is not used
+    let (fee_config_pda, _bump_seed) = derive_fee_config_pda(&spl_token::native_mint::ID.to_bytes(), &program_id);
+    account_metas.push(AccountMeta::new_readonly(fee_config_pda, false));
+    account_metas.push(AccountMeta::new(receiver_pubkey, false));
     account_metas.push(AccountMeta::new(receiver_pubkey, false));
     account_metas.push(AccountMeta::new(receiver_token_addr, false));
-    let (funding_ata, ata_bump) = Pubkey::find_program_address(&[b"funding_ata"], &program_id);
-    account_metas.push(AccountMeta::new(funding_ata, false));
+    let (vault_pda, _vault_bump) =
+        derive_vault_pda(&spl_token::native_mint::ID, &spl_token::ID, &program_id);
+    account_metas.push(AccountMeta::new(vault_pda, false));
     account_metas.push(AccountMeta::new_readonly(spl_token::ID, false));
     account_metas.push(AccountMeta::new(
         verification_program_id,
         false,
     ));
+    account_metas.push(AccountMeta::new_readonly(vk_registry_pda, false));
+    account_metas.push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+    // one spent commitments account per spent UTXO
+    let (spent_commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
+    nullifiers.iter().for_each(|_| {
+        account_metas.push(AccountMeta::new(spent_commitments_pda, false));
+    });
+    // one nullifier account per spent UTXO
+    nullifiers.iter().for_each(|nullifier| {
+        let (nullifier_pda, _bump_seed) = derive_nullifier_pda(nullifier, &program_id);
+        account_metas.push(AccountMeta::new(nullifier_pda, false));
+    });
     let (current_commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
     account_metas.push(AccountMeta::new_readonly(current_commitments_pda, false));
     // insert variant bytes
@@ -785,6 +868,140 @@ async fn test_process_instruction_withdraw() {
     }
 }
 
+#[tokio::test]
+async fn test_process_instruction_native_deposit() {
+    let rpc_client = RpcClient::new_with_commitment(
+        String::from("http://127.0.0.1:8899"),
+        CommitmentConfig::confirmed(),
+    );
+
+    let program_id = pubkey!("GiEEuDqgmeW7GFUf7rHwFxCmYocEe9j4CfRydGJcMBaS");
+    let verification_program_id = pubkey!("8aEyNmun78dxGvRDKt7K1Gik1QLKKQu5GYLyZMMhyvU");
+
+    let depositor_keypair = solana_sdk::signature::Keypair::new();
+    let depositor_pubkey = depositor_keypair.pubkey();
+
+    let depositor_deposit_key = solana_sdk::signature::Keypair::new();
+    let depositor_view_key = solana_sdk::signature::Keypair::new();
+    let depositor_spend_key = solana_sdk::signature::Keypair::new();
+
+    let transaction_signature = rpc_client
+        .request_airdrop(&depositor_pubkey, 100 * solana_sdk::native_token::LAMPORTS_PER_SOL)
+        .await.unwrap();
+    loop {
+        if rpc_client.confirm_transaction(&transaction_signature).await.unwrap() {
+            break;
+        }
+    }
+
+    // initialize
+
+    // only the upgrade authority ( the deployer ) can initialize the program
+    let upgrade_authority = load_upgrade_authority();
+    let mut account_metas: Vec<AccountMeta> = vec![];
+    account_metas.push(AccountMeta::new(upgrade_authority.pubkey(), true));
+    let (funding_pda, _bump_seed) = Pubkey::find_program_address(&[b"funding_pda"], &program_id);
+    account_metas.push(AccountMeta::new(funding_pda, false));
+    let (commitments_pda, _bump_seed) = derive_pda(1, &program_id);
+    account_metas.push(AccountMeta::new(commitments_pda, false));
+    let (commitments_manager_pda, _bump_seed) =
+        Pubkey::find_program_address(&[b"commitments_manager_pda"], &program_id);
+    account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new(SYSTEM_PROGRAM_ID, false));
+    let (config_pda, _bump_seed) = derive_config_pda(&program_id);
+    account_metas.push(AccountMeta::new(config_pda, false));
+    let (program_data, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    account_metas.push(AccountMeta::new_readonly(program_data, false));
+    // proofs are only verified with the verification program pinned at initialize
+    account_metas.push(AccountMeta::new_readonly(verification_program_id, false));
+
+    let instruction = Instruction {
+        program_id,
+        accounts: account_metas,
+        data: vec![3],
+    };
+
+    let mut transaction =
+        Transaction::new_with_payer(&[instruction], Some(&upgrade_authority.pubkey()));
+
+    transaction.sign(&[&upgrade_authority], rpc_client.get_latest_blockhash().await.unwrap());
+    rpc_client.send_and_confirm_transaction(&transaction).await.unwrap();
+
+    // deposit native SOL, no token account or wrapping is needed
+    let amount = 1 * 10_u64.pow(9);
+    let native_token_id = Pubkey::new_from_array(NATIVE_SOL_TOKEN_ID);
+
+    let (mut deposit_data, deposit_utxo, _deposit_random) = create_deposit_instructions_data_test(
+        &native_token_id,
+        amount,
+        depositor_spend_key.pubkey().to_bytes().to_vec(),
+        depositor_view_key.pubkey().to_bytes().to_vec(),
+        depositor_deposit_key.pubkey().to_bytes().to_vec(),
+        "test native deposit".to_string(),
+    ).unwrap();
+
+    // get all necessary account meta
+    // funding_account
+    // user_wallet
+    // native_vault
+    // commitments_account
+    // commitments_manager_account
+    // system_program
+    let tree_number = 1;
+    let mut account_metas: Vec<AccountMeta> = vec![];
+    account_metas.push(AccountMeta::new(funding_pda, false));
+    account_metas.push(AccountMeta::new(depositor_pubkey, true));
+    let (native_vault_pda, _bump_seed) = derive_native_vault_pda(&program_id);
+    account_metas.push(AccountMeta::new(native_vault_pda, false));
+    let (commitments_pda, _bump_seed) = derive_pda(tree_number, &program_id);
+    account_metas.push(AccountMeta::new(commitments_pda, false));
+    account_metas.push(AccountMeta::new(commitments_manager_pda, false));
+    account_metas.push(AccountMeta::new_readonly(config_pda, false));
+    // no fee is configured for the token, the treasury is not used
+    let (fee_config_pda, _bump_seed) = derive_fee_config_pda(&NATIVE_SOL_TOKEN_ID, &program_id);
+    account_metas.push(AccountMeta::new_readonly(fee_config_pda, false));
+    account_metas.push(AccountMeta::new(depositor_pubkey, false));
+    account_metas.push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+
+    // insert variant bytes
+    deposit_data.insert(0, 0);
+    let instruction = Instruction {
+        program_id,
+        accounts: account_metas,
+        data: deposit_data,
+    };
+
+    let message = Message::new(&[instruction], Some(&depositor_pubkey));
+    let mut transaction = Transaction::new_unsigned(message);
+
+    transaction.sign(&[&depositor_keypair], rpc_client.get_latest_blockhash().await.unwrap());
+
+    let res = rpc_client.send_and_confirm_transaction(&transaction).await;
+
+    match res {
+        Ok(_) => println!("Native deposit transaction successful"),
+        Err(err) => panic!("Native deposit transaction failed: {:?}", err),
+    };
+
+    let vault_balance = rpc_client.get_balance(&native_vault_pda).await.unwrap();
+    assert!(vault_balance >= amount);
+
+    let commitments_root = match rpc_client.get_account(&commitments_pda).await {
+        Ok(account) => {
+            let commitments_account = CommitmentsAccount::load(&account.data[..]).unwrap();
+            assert!(commitments_account.next_leaf_index() == 1);
+            commitments_account.root()
+        },
+        Err(e) => panic!("Failed to get account data: {}", e),
+    };
+
+    let mut tree = MerkleTreeSparse::<16>::new(1);
+    let pre_commitment = PreCommitments::new(amount, NATIVE_SOL_TOKEN_ID.to_vec(), deposit_utxo.utxo_public_key());
+    tree.insert(vec![hash_precommits(pre_commitment)]);
+    assert_eq!(tree.root(), commitments_root);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -3,11 +3,18 @@ use std::panic;
 use borsh::BorshSerialize;
 use darksol::{CommitmentCipherText, DepositRequest, PreCommitments, ShieldCipherText};
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer, system_instruction, transaction::Transaction};
+use solana_sdk_ids::bpf_loader_upgradeable;
+use solana_sdk_ids::system_program::ID as SYSTEM_PROGRAM_ID;
+use solana_sdk::{instruction::{AccountMeta, Instruction}, pubkey::Pubkey, signature::{read_keypair_file, Keypair}, signer::Signer, system_instruction, transaction::Transaction};
 use sp1_sdk::{ProverClient, SP1ProofWithPublicValues, SP1Stdin};
 use spl_associated_token_account::{get_associated_token_address, instruction::create_associated_token_account_idempotent};
 use spl_token::instruction::sync_native;
-use veil_types::{blind_keys, keccak, sha256, share_key, Arguments, CipherText, CommitmentPlainText, MerkleTreeSparse, PrivateData, PublicData, UTXO};
+use verification::instruction::VerificationInstruction;
+use verification::registry::{derive_vk_registry_pda, CircuitVk, OPERATION_TRANSFER, OPERATION_WITHDRAW};
+use verification::utils::decode_sp1_vkey_hash;
+use verification::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+use verification::METHOD_VKEY_HASH;
+use veil_types::{blind_keys, sha256, share_key, Arguments, CipherText, CommitmentPlainText, MerkleTreeSparse, PrivateData, PublicData, UTXO};
 use aes_gcm::{
     aead::Aead, aes::cipher::generic_array::typenum::U12, Aes256Gcm, Key, KeyInit, Nonce,
 };
@@ -15,6 +22,66 @@ use rand::Rng;
 
 use crate::METHODS_ELF;
 
+// load_upgrade_authority loads the default solana cli keypair
+// which deploys the program in scripts/test.sh
+pub fn load_upgrade_authority() -> Keypair {
+    let home = std::env::var("HOME").expect("HOME is not set");
+    read_keypair_file(format!("{}/.config/solana/id.json", home))
+        .expect("failed to read the upgrade authority keypair")
+}
+
+// CIRCUIT_ID is the circuit version the test proofs are generated with
+pub const CIRCUIT_ID: u32 = 1;
+
+// register_circuit initializes the verification key registry if needed
+// and registers the test circuit for transfer and withdraw, signed by the
+// upgrade authority. Both operations are proven with the same program
+pub async fn register_circuit(client: &RpcClient, verification_program_id: &Pubkey) {
+    let upgrade_authority = load_upgrade_authority();
+    let (vk_registry_pda, _bump_seed) = derive_vk_registry_pda(verification_program_id);
+
+    let mut instructions = vec![];
+    if client.get_account(&vk_registry_pda).await.is_err() {
+        let (program_data, _bump_seed) = Pubkey::find_program_address(
+            &[verification_program_id.as_ref()],
+            &bpf_loader_upgradeable::id(),
+        );
+        instructions.push(Instruction::new_with_borsh(
+            *verification_program_id,
+            &VerificationInstruction::InitializeRegistry,
+            vec![
+                AccountMeta::new(upgrade_authority.pubkey(), true),
+                AccountMeta::new(vk_registry_pda, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+                AccountMeta::new_readonly(program_data, false),
+            ],
+        ));
+    }
+
+    for operation in [OPERATION_TRANSFER, OPERATION_WITHDRAW] {
+        let circuit = CircuitVk {
+            operation,
+            circuit_id: CIRCUIT_ID,
+            sp1_vkey_hash: decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap(),
+            groth16_vk: GROTH16_VK_4_0_0_RC3_BYTES.to_vec(),
+        };
+        instructions.push(Instruction::new_with_borsh(
+            *verification_program_id,
+            &VerificationInstruction::SetCircuit { circuit },
+            vec![
+                AccountMeta::new(upgrade_authority.pubkey(), true),
+                AccountMeta::new(vk_registry_pda, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+            ],
+        ));
+    }
+
+    let mut transaction =
+        Transaction::new_with_payer(&instructions, Some(&upgrade_authority.pubkey()));
+    transaction.sign(&[&upgrade_authority], client.get_latest_blockhash().await.unwrap());
+    client.send_and_confirm_transaction(&transaction).await.unwrap();
+}
+
 pub fn generate_random_bytes(length: usize) -> Vec<u8> {
     let mut rng = rand::rng();
     (0..length).map(|_| rng.random()).collect()
@@ -68,7 +135,8 @@ pub fn generate_proof_transfer(
     receiver_viewing_pubkey: Vec<Vec<u8>>,
     receivers_master_pubkey: Vec<Vec<u8>>,
     token_id: &Pubkey,
-    sender_viewing_key: &Keypair
+    sender_viewing_key: &Keypair,
+    params_hash: Vec<u8>,
 ) -> (SP1ProofWithPublicValues, Vec<Vec<u8>>, Vec<CommitmentCipherText>, Vec<Vec<u8>>) {
     let sum_in = amounts_in.iter().sum::<u64>();
     let sum_out = amounts_out.iter().sum::<u64>();
@@ -133,7 +201,6 @@ pub fn generate_proof_transfer(
         new_amounts_out.push(*amount_out);
     });
     // amounts_out.push(amount_out);
-    let params_hash = keccak(vec![&[100]]);
     let merkle_root = tree.root();
     let signature = utxos_in[0].sign(
         merkle_root.clone(),
@@ -222,6 +289,7 @@ pub fn generate_proof_withdraw(
     sender_view_key: &Keypair,
     receiver_spend_key: &Keypair,
     receiver_view_key: &Keypair,
+    params_hash: Vec<u8>,
 ) -> (SP1ProofWithPublicValues, Vec<Vec<u8>>, Option<CommitmentCipherText>, Vec<Vec<u8>>) {
     let sum_in = amounts_in.iter().sum::<u64>();
     let merkle_proofs = leaves
@@ -289,7 +357,6 @@ pub fn generate_proof_withdraw(
         )
     );
     let output_hashes: Vec<Vec<u8>> = utxos_out.iter().map(|utxo| utxo.utxo_hash()).collect();
-    let params_hash = keccak(vec![&[100]]);
     let merkle_root = tree.root();
     let signature = utxos_in[0].sign(
         merkle_root.clone(),
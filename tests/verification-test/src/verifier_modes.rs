@@ -0,0 +1,191 @@
+#[cfg(feature = "in-process-verification")]
+use darksol::verifier::verify_proof_in_process;
+use darksol::verifier::{verify_proof, verify_proof_cpi};
+use darksol::{SP1Groth16Proof, OPERATION_TRANSFER, OPERATION_WITHDRAW, VERIFY_INSTRUCTION};
+use solana_program::account_info::AccountInfo;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::instruction::Instruction;
+use solana_program::program_error::ProgramError;
+use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+use solana_program::pubkey::Pubkey;
+use std::sync::Once;
+use sp1_sdk::SP1ProofWithPublicValues;
+use verification::registry::{derive_vk_registry_pda, CircuitVk, VkRegistry};
+use verification::utils::decode_sp1_vkey_hash;
+use verification::verify_proof::GROTH16_VK_4_0_0_RC3_BYTES;
+use verification::METHOD_VKEY_HASH;
+
+const CIRCUIT_ID: u32 = 1;
+
+// Case is a proof submitted to both verification paths
+struct Case {
+    name: &'static str,
+    proof: SP1Groth16Proof,
+    accepted: bool,
+}
+
+fn cases() -> Vec<Case> {
+    let sp1_proof = SP1ProofWithPublicValues::load("bin/methods_proof.bin").unwrap();
+    let proof = sp1_proof.bytes();
+    let sp1_public_inputs = sp1_proof.public_values.to_vec();
+
+    let groth16_proof = |operation: u8, circuit_id: u32, proof: &[u8], sp1_public_inputs: &[u8]| {
+        SP1Groth16Proof {
+            operation,
+            circuit_id,
+            proof: proof.to_vec(),
+            sp1_public_inputs: sp1_public_inputs.to_vec(),
+        }
+    };
+
+    let mut tampered_inputs = sp1_public_inputs.clone();
+    tampered_inputs[0] ^= 1;
+    let mut tampered_proof = proof.clone();
+    let last = tampered_proof.len() - 1;
+    tampered_proof[last] ^= 1;
+
+    vec![
+        Case {
+            name: "valid proof",
+            proof: groth16_proof(OPERATION_TRANSFER, CIRCUIT_ID, &proof, &sp1_public_inputs),
+            accepted: true,
+        },
+        Case {
+            name: "tampered public inputs",
+            proof: groth16_proof(OPERATION_TRANSFER, CIRCUIT_ID, &proof, &tampered_inputs),
+            accepted: false,
+        },
+        Case {
+            name: "tampered proof",
+            proof: groth16_proof(OPERATION_TRANSFER, CIRCUIT_ID, &tampered_proof, &sp1_public_inputs),
+            accepted: false,
+        },
+        Case {
+            name: "unknown circuit",
+            proof: groth16_proof(OPERATION_TRANSFER, CIRCUIT_ID + 1, &proof, &sp1_public_inputs),
+            accepted: false,
+        },
+        Case {
+            name: "circuit of another operation",
+            proof: groth16_proof(OPERATION_WITHDRAW, CIRCUIT_ID, &proof, &sp1_public_inputs),
+            accepted: false,
+        },
+    ]
+}
+
+// verify_cpi runs the verification program instruction DarkSol invokes
+// through the CPI, with the same instruction data
+fn verify_cpi(verification_program_id: &Pubkey, registry: &AccountInfo, proof: &SP1Groth16Proof) -> ProgramResult {
+    let instruction_data = borsh::to_vec(&(VERIFY_INSTRUCTION, proof)).unwrap();
+    verification::process_instruction(verification_program_id, std::slice::from_ref(registry), &instruction_data)
+}
+
+// VerificationCpi routes the CPIs DarkSol makes off chain to the verification
+// program, the only program its verifier invokes
+struct VerificationCpi;
+
+impl SyscallStubs for VerificationCpi {
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        // the callee takes the accounts in the instruction order
+        let accounts = instruction
+            .accounts
+            .iter()
+            .map(|meta| {
+                account_infos
+                    .iter()
+                    .find(|account| account.key == &meta.pubkey)
+                    .cloned()
+                    .ok_or(ProgramError::NotEnoughAccountKeys)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        verification::process_instruction(&instruction.program_id, &accounts, &instruction.data)
+    }
+}
+
+fn set_verification_cpi() {
+    static SET_STUBS: Once = Once::new();
+    SET_STUBS.call_once(|| {
+        set_syscall_stubs(Box::new(VerificationCpi));
+    });
+}
+
+#[test]
+fn test_cpi_and_in_process_verification_agree() {
+    let verification_program_id = Pubkey::new_unique();
+    let (registry_pda, _bump_seed) = derive_vk_registry_pda(&verification_program_id);
+
+    // only the transfer circuit is registered
+    let registry = VkRegistry {
+        admin: Pubkey::new_unique(),
+        circuits: vec![CircuitVk {
+            operation: OPERATION_TRANSFER,
+            circuit_id: CIRCUIT_ID,
+            sp1_vkey_hash: decode_sp1_vkey_hash(METHOD_VKEY_HASH).unwrap(),
+            groth16_vk: GROTH16_VK_4_0_0_RC3_BYTES.to_vec(),
+        }],
+    };
+    let mut registry_data = borsh::to_vec(&registry).unwrap();
+    let mut registry_lamports = 0;
+    let registry_account = AccountInfo::new(
+        &registry_pda,
+        false,
+        false,
+        &mut registry_lamports,
+        &mut registry_data,
+        &verification_program_id,
+        false,
+        0,
+    );
+
+    let mut verification_program_lamports = 0;
+    let mut verification_program_data = vec![];
+    let loader_id = solana_sdk_ids::bpf_loader_upgradeable::id();
+    let verification_program = AccountInfo::new(
+        &verification_program_id,
+        false,
+        false,
+        &mut verification_program_lamports,
+        &mut verification_program_data,
+        &loader_id,
+        true,
+        0,
+    );
+
+    set_verification_cpi();
+
+    for case in cases() {
+        let instruction_result = verify_cpi(&verification_program_id, &registry_account, &case.proof);
+        assert_eq!(instruction_result.is_ok(), case.accepted, "instruction: {}", case.name);
+
+        // DarkSol's CPI path is compiled in every build
+        let cpi_result =
+            verify_proof_cpi(&verification_program, &registry_account, case.proof.clone());
+        assert_eq!(cpi_result, instruction_result, "cpi: {}", case.name);
+
+        #[cfg(feature = "in-process-verification")]
+        {
+            let in_process_result =
+                verify_proof_in_process(&verification_program, &registry_account, case.proof.clone());
+            assert_eq!(in_process_result, cpi_result, "in process: {}", case.name);
+        }
+
+        // the path the program is built with, the CPI path without the
+        // in-process-verification feature
+        let proof = case.proof;
+        let verify_result = verify_proof(
+            &verification_program,
+            &registry_account,
+            proof.operation,
+            proof.circuit_id,
+            proof.proof,
+            proof.sp1_public_inputs,
+        );
+        assert_eq!(verify_result, cpi_result, "verify proof: {}", case.name);
+    }
+}